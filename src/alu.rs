@@ -1,4 +1,6 @@
-use std::{collections::HashSet, hash::Hash};
+use std::{collections::{HashMap, HashSet}, error::Error, hash::Hash, path::Path};
+
+use serde::{Deserialize, Serialize};
 
 use crate::scalar::Scalar;
 
@@ -7,17 +9,74 @@ use crate::scalar::Scalar;
 #[allow(non_camel_case_types)]
 #[derive(Clone, Copy, Debug)]
 pub enum ALUInput {
-    PREV(usize), PREV_BELOW(usize), NEXT(usize), CONSTANT(Scalar) // The usize is the index of the vector input to use. 
+    PREV(usize), PREV_BELOW(usize), NEXT(usize), CONSTANT(Scalar), // The usize is the index of the vector input to use. 
+    PREV_STRIDE(usize, usize, Scalar), // Lane i reads lane i + stride of the register. Lanes past the end read the padding.
+    SCALAR(usize), // The value of the scalar input, broadcast to all lanes.
+    COUNTER(usize) // The value of the counter at the given level of the counter chain.
 }
 
-#[derive(PartialEq, Eq, Hash, Copy, Clone)]
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug, Serialize, Deserialize)]
 #[allow(non_camel_case_types)]
 pub enum ALUOp {
     ADD_I32, SUB_I32, MUL_I32, DIV_I32,
     ADD_FP32, SUB_FP32, MUL_FP32, DIV_FP32
 }
 
-#[derive(Clone, Copy)]
+// Timing of a single operation on an ALU.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct ALUOpTiming {
+    pub latency: usize,             // Cycles from issue until the result is available.
+    pub initiation_interval: usize  // Cycles until the ALU accepts the next operation.
+}
+
+// Per-op timing table of an ALU. Ops that are missing from the table use ALUOp::default_timing().
+// The table can be loaded from a JSON file of the form {"ADD_I32": {"latency": 1, "initiation_interval": 1}, ...}.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ALUTimingTable {
+    table: HashMap<ALUOp, ALUOpTiming>
+}
+
+impl Default for ALUTimingTable {
+    fn default() -> Self {
+        ALUTimingTable {
+            table: ALUOp::ALL.iter().map(|op| (*op, op.default_timing())).collect()
+        }
+    }
+}
+
+impl ALUTimingTable {
+    pub fn get(&self, op: ALUOp) -> ALUOpTiming {
+        self.table.get(&op).copied().unwrap_or(op.default_timing())
+    }
+
+    pub fn latency(&self, op: ALUOp) -> usize {
+        self.get(op).latency
+    }
+
+    pub fn initiation_interval(&self, op: ALUOp) -> usize {
+        self.get(op).initiation_interval
+    }
+
+    pub fn set(&mut self, op: ALUOp, timing: ALUOpTiming) {
+        self.table.insert(op, timing);
+    }
+
+    // Entries in the JSON override the defaults, everything else keeps its default timing.
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        let overrides: HashMap<ALUOp, ALUOpTiming> = serde_json::from_str(json)?;
+        let mut table = Self::default();
+        table.table.extend(overrides);
+        Ok(table)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(Self::from_json(&json)?)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 pub struct ALURtConfig {
     pub op: ALUOp,
     pub in_a: ALUInput,
@@ -32,22 +91,49 @@ impl ALURtConfig {
             match input {
                 ALUInput::PREV(i) => input_regs.push(*i),
                 ALUInput::PREV_BELOW(i) => input_regs.push(*i),
+                ALUInput::PREV_STRIDE(i, _, _) => input_regs.push(*i),
                 ALUInput::NEXT(i) => input_regs.push(*i),
-                ALUInput::CONSTANT(_) => ()
+                ALUInput::CONSTANT(_) | ALUInput::SCALAR(_) | ALUInput::COUNTER(_) => ()
             }
         }
         input_regs.into_iter().collect()
     }
+
+    // Registers of the previous stage (or input ports, for the first stage) this ALU reads.
+    pub fn get_prev_regs(&self) -> HashSet<usize> {
+        [self.in_a, self.in_b].iter().filter_map(|input| match input {
+            ALUInput::PREV(i) | ALUInput::PREV_BELOW(i) | ALUInput::PREV_STRIDE(i, _, _) => Some(*i),
+            _ => None
+        }).collect()
+    }
+
+    pub fn get_scalar_inputs(&self) -> HashSet<usize> {
+        [self.in_a, self.in_b].iter().filter_map(|input| match input {
+            ALUInput::SCALAR(i) => Some(*i),
+            _ => None
+        }).collect()
+    }
+
+    pub fn reads_next(&self) -> bool {
+        [self.in_a, self.in_b].iter().any(|input| matches!(input, ALUInput::NEXT(_)))
+    }
 }
 
 #[derive(Clone)]
 pub struct ALUHwConfig {
-    pub supported_ops: HashSet<ALUOp>
+    pub supported_ops: HashSet<ALUOp>,
+    pub timing: ALUTimingTable
 }
 
 impl ALUOp { 
-    pub fn delay(&self) -> usize { 
-        match self { // TODO: These values are guessed.
+    pub const ALL: [ALUOp; 8] = [
+        Self::ADD_I32, Self::SUB_I32, Self::MUL_I32, Self::DIV_I32,
+        Self::ADD_FP32, Self::SUB_FP32, Self::MUL_FP32, Self::DIV_FP32
+    ];
+
+    // Default timing, used when an ALUTimingTable does not override an op.
+    pub fn default_timing(&self) -> ALUOpTiming { 
+        let latency = match self { // TODO: These values are guessed.
             Self::ADD_I32 => 1,
             Self::SUB_I32 => 1,
             Self::MUL_I32 => 2,
@@ -56,7 +142,8 @@ impl ALUOp {
             Self::SUB_FP32 => 2,
             Self::MUL_FP32 => 3,
             Self::DIV_FP32 => 5
-        }
+        };
+        ALUOpTiming { latency: latency, initiation_interval: 1 }
     }
 
     pub fn apply(&self, lhs: &Scalar, rhs: &Scalar) -> Scalar {
@@ -77,7 +164,7 @@ impl ALUOp {
 #[cfg(test)]
 mod tests {
     use crate::scalar::Scalar;
-    use super::ALUOp;
+    use super::{ALUOp, ALUOpTiming, ALUTimingTable};
 
     #[test]
     fn test_alu_op_int_add() {
@@ -91,4 +178,12 @@ mod tests {
             assert!(false, "Wrong resulting type.")
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_timing_table_from_json() {
+        let table = ALUTimingTable::from_json(
+            r#"{"MUL_I32": {"latency": 7, "initiation_interval": 2}}"#).unwrap();
+        assert_eq!(table.get(ALUOp::MUL_I32), ALUOpTiming { latency: 7, initiation_interval: 2 });
+        assert_eq!(table.get(ALUOp::ADD_I32), ALUOp::ADD_I32.default_timing());
+    }
+}
@@ -0,0 +1,66 @@
+// Helpers for contexts that read several channels at once, e.g. the switches and the global network.
+use dam::{channel::{ChannelElement, PeekResult, Receiver}, structures::TimeManager, types::DAMType};
+
+// Waits until the producer of `receiver` got to the current cycle, so that everything it sends up to this 
+// cycle is visible, and returns what the receiver holds then. Time does not move. The wait blocks on the 
+// producer's time rather than on its data: a producer that lags behind may still have nothing to send.
+pub fn wait_for_producer<T: DAMType>(receiver: &Receiver<T>, time: &TimeManager) -> PeekResult<T> {
+    match receiver.peek() {
+        PeekResult::Nothing(producer_time) if producer_time < time.tick() => {
+            receiver.wait_until(time.tick());
+            receiver.peek()
+        },
+        result => result
+    }
+}
+
+pub enum InputWait {
+    Available(Vec<usize>), // the earliest element is available on these inputs
+    Closed,                // every input closed
+    Waited                 // time moved on, look again
+}
+
+// One step of waiting for the earliest element on any of `receivers`.
+// An empty input reports up to which time its producer got, so nothing can arrive on it before then.
+pub fn wait_for_earliest<T: DAMType>(receivers: &[Receiver<T>], time: &TimeManager) -> InputWait {
+    let peek_results: Vec<(usize, PeekResult<T>)> = receivers.iter()
+        .map(|r| r.peek())
+        .enumerate()
+        .filter(|(_, x)| !matches!(x, PeekResult::Closed))
+        .collect();
+    let time_of = |x: &PeekResult<T>| match x {
+        PeekResult::Something(ChannelElement { time, data: _ }) => time.clone(),
+        PeekResult::Nothing(time) => time.clone(),
+        PeekResult::Closed => unreachable!()
+    };
+    let Some(min) = peek_results.iter().map(|(_, x)| time_of(x)).min() else {
+        return InputWait::Closed;
+    };
+
+    let first_channels: Vec<&(usize, PeekResult<T>)> = peek_results.iter().filter(|(_, x)| time_of(x) == min).collect();
+    let avail_channels: Vec<usize> = first_channels.iter()
+        .filter(|(_, x)| matches!(x, PeekResult::Something(_)))
+        .map(|(i, _)| *i)
+        .collect();
+    if !avail_channels.is_empty() {
+        return InputWait::Available(avail_channels);
+    }
+
+    // The earliest input is empty.
+    let tick = time.tick();
+    if min > tick {
+        // Its producer is ahead of us: skip the idle cycles in one step.
+        time.advance(min);
+    } else if min == tick {
+        // Its producer is at our cycle. A link takes at least a cycle, so nothing else arrives in this one. 
+        // If the producer does not follow, the next look waits for it instead of running further ahead.
+        time.incr_cycles(1);
+    } else {
+        // Its producer is behind us, e.g. because it was not scheduled yet, and may still send something for 
+        // a cycle we already got to. Wait for its time rather than for its data: the link may stay idle.
+        for (input, _) in first_channels {
+            let _ = wait_for_producer(&receivers[*input], time);
+        }
+    }
+    InputWait::Waited
+}
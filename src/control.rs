@@ -0,0 +1,81 @@
+// Control network: a separate bit-level network that carries enable and done tokens between units, 
+// so that coarse-grained pipelines can be sequenced. PCUs and switches have control ports; the PMU is not 
+// modelled yet, so it has none.
+use std::fmt::Debug;
+
+use dam::{channel::{ChannelElement, PeekResult, Receiver, Sender}, context::Context, structures::{Time, TimeManager}, types::DAMType};
+
+// Control wires carry single bits, so a token has no payload.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ControlToken;
+
+impl DAMType for ControlToken {
+    fn dam_size(&self) -> usize {
+        1
+    }
+}
+
+pub enum ControlPort {
+    Input(Receiver<ControlToken>),
+    Output(Sender<ControlToken>)
+}
+
+impl ControlPort {
+    pub fn attach<C: Context>(&self, context: &C) {
+        match self {
+            ControlPort::Input(receiver) => receiver.attach_receiver(context),
+            ControlPort::Output(sender) => sender.attach_sender(context)
+        }
+    }
+
+    pub fn is_input(&self) -> bool {
+        matches!(self, ControlPort::Input(_))
+    }
+
+    // Blocks until a token arrives. Returns false if the control stream is closed.
+    pub fn wait(&self, time: &TimeManager) -> bool {
+        match self {
+            ControlPort::Input(receiver) => receiver.dequeue(time).is_ok(),
+            ControlPort::Output(_) => panic!("Cannot wait for a token on a control output.")
+        }
+    }
+
+    pub fn signal(&self, time: &TimeManager, at: Time) {
+        match self {
+            ControlPort::Output(sender) => sender.enqueue(time, ChannelElement::new(at, ControlToken)).unwrap(),
+            ControlPort::Input(_) => panic!("Cannot send a token on a control input.")
+        }
+    }
+}
+
+// Loads a new runtime configuration into a unit while the simulation runs, e.g. to time-multiplex a program.
+// The unit stops for `latency` cycles once the load arrived and its current work is done, then continues 
+// with `config`.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigLoad<C> {
+    pub config: C,
+    pub latency: usize
+}
+
+// A load counts as a single message on the config network, whatever the size of the configuration.
+impl<C: Clone + Debug + Default + Send + Sync + 'static> DAMType for ConfigLoad<C> {
+    fn dam_size(&self) -> usize {
+        1
+    }
+}
+
+// Returns the next config load if it arrived by `until`. Never blocks: the controller that sends the loads 
+// may itself wait for the unit, e.g. for a done token, so a sender that has not caught up yet simply means 
+// that there is no load yet. A load sent ahead of the data on the same path is always seen before the data.
+pub fn poll_config_load<C: Clone + Debug + Default + Send + Sync + 'static>(port: &Receiver<ConfigLoad<C>>, time: &TimeManager, until: &Time) -> Option<ChannelElement<ConfigLoad<C>>> {
+    match port.peek() {
+        PeekResult::Something(ChannelElement { time: t, data: _ }) if t <= *until => port.dequeue(time).ok(),
+        _ => None
+    }
+}
+
+// Both the switch and the PCU take a load once it arrived and they finished the work they were busy with 
+// until `busy_until`. The new configuration is ready `latency` cycles later.
+pub fn reconfigured_at<C: Clone + Debug + Default + Send + Sync + 'static>(load: &ChannelElement<ConfigLoad<C>>, busy_until: Time) -> Time {
+    std::cmp::max(load.time.clone(), busy_until) + load.data.latency as u64
+}
@@ -0,0 +1,152 @@
+// Counter chain of a PCU, generating the iteration indices of a loop nest.
+use crate::scalar::Scalar;
+
+// A single loop level, counting from min (inclusive) to max (exclusive).
+// The innermost counter of a chain is vectorized: it fills `par` SIMD lanes per iteration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CounterConfig {
+    pub min: i32,
+    pub max: i32,
+    pub stride: i32,
+    pub par: usize
+}
+
+// Counter values of one iteration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CounterValues {
+    pub values: Vec<Vec<Scalar>>, // values[level][lane]
+    pub last: Vec<bool>,          // last[level]: this is the last iteration of the level's loop
+    pub mask: Vec<bool>           // mask[lane]: the lane holds an iteration of the innermost loop
+}
+
+impl CounterValues {
+    // The chain finishes once every level is in its last iteration.
+    pub fn is_last(&self) -> bool {
+        self.last.iter().all(|l| *l)
+    }
+}
+
+// Nested counters; configs[0] is the outermost loop.
+pub struct CounterChain {
+    configs: Vec<CounterConfig>,
+    values: Vec<i32>,
+    done: bool
+}
+
+impl CounterChain {
+    pub fn new(configs: Vec<CounterConfig>) -> CounterChain {
+        assert!(!configs.is_empty(), "A counter chain needs at least one counter.");
+        assert!(configs.iter().all(|c| c.stride > 0 && c.par > 0), "Counters need a positive stride and parallelization.");
+        let mut chain = CounterChain { configs: configs, values: vec![], done: false };
+        chain.reset();
+        chain
+    }
+
+    pub fn reset(&mut self) {
+        self.values = self.configs.iter().map(|c| c.min).collect();
+        self.done = self.configs.iter().any(|c| c.min >= c.max);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.configs.len()
+    }
+
+    fn innermost(&self) -> usize {
+        self.configs.len() - 1
+    }
+
+    fn increment(&self, level: usize) -> i32 {
+        let config = &self.configs[level];
+        if level == self.innermost() { config.stride * config.par as i32 } else { config.stride }
+    }
+
+    // Number of lanes the innermost counter fills in the current iteration.
+    // This is smaller than `par` in the tail iteration of a loop whose trip count is not a multiple of `par`.
+    pub fn active_lanes(&self) -> usize {
+        let config = &self.configs[self.innermost()];
+        let remaining = (config.max - self.values[self.innermost()] + config.stride - 1) / config.stride;
+        std::cmp::min(config.par, remaining.max(0) as usize)
+    }
+
+    // Returns the counter values of the next iteration, or None once the outermost loop finished.
+    // Lanes beyond the parallelization of the innermost counter repeat the base value. They are masked off,
+    // just as the lanes past the trip count in the tail iteration.
+    pub fn next(&mut self, num_simd_lanes: usize) -> Option<CounterValues> {
+        if self.done {
+            return None;
+        }
+
+        let innermost = self.innermost();
+        let values: Vec<Vec<Scalar>> = self.configs.iter().enumerate().map(|(level, config)| {
+            (0..num_simd_lanes).map(|lane| {
+                if level == innermost && lane < config.par {
+                    Scalar::I32(self.values[level] + lane as i32 * config.stride)
+                } else {
+                    Scalar::I32(self.values[level])
+                }
+            }).collect::<Vec<Scalar>>()
+        }).collect();
+        let last: Vec<bool> = (0..self.configs.len())
+            .map(|level| self.values[level] + self.increment(level) >= self.configs[level].max)
+            .collect();
+        let active_lanes = self.active_lanes();
+        let mask: Vec<bool> = (0..num_simd_lanes).map(|lane| lane < active_lanes).collect();
+
+        // Advance the innermost counter and carry into the outer ones.
+        let mut level = innermost;
+        loop {
+            self.values[level] += self.increment(level);
+            if self.values[level] < self.configs[level].max {
+                break;
+            }
+            self.values[level] = self.configs[level].min;
+            if level == 0 {
+                self.done = true;
+                break;
+            }
+            level -= 1;
+        }
+
+        Some(CounterValues { values: values, last: last, mask: mask })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scalar::Scalar;
+
+    use super::{CounterChain, CounterConfig};
+
+    #[test]
+    fn nested_parallel_counters_test() {
+        let mut chain = CounterChain::new(vec![
+            CounterConfig { min: 0, max: 2, stride: 1, par: 1 },
+            CounterConfig { min: 0, max: 6, stride: 1, par: 4 },
+        ]);
+
+        let lanes = |xs: [i32; 4]| xs.iter().map(|x| Scalar::I32(*x)).collect::<Vec<_>>();
+
+        let first = chain.next(4).unwrap();
+        assert_eq!(first.values, vec![lanes([0, 0, 0, 0]), lanes([0, 1, 2, 3])]);
+        assert_eq!(first.last, vec![false, false]);
+        assert_eq!(first.mask, vec![true; 4]);
+
+        let second = chain.next(4).unwrap();
+        assert_eq!(second.values[1], lanes([4, 5, 6, 7]));
+        assert_eq!(second.last, vec![false, true]);
+
+        let third = chain.next(4).unwrap();
+        assert_eq!(third.values[0], lanes([1, 1, 1, 1]));
+        assert!(!third.is_last());
+        assert_eq!(chain.active_lanes(), 2);
+        let fourth = chain.next(4).unwrap();
+        assert!(fourth.is_last());
+        assert_eq!(fourth.mask, vec![true, true, false, false]);
+        assert!(chain.next(4).is_none());
+        assert!(chain.is_done());
+    }
+}
@@ -0,0 +1,754 @@
+// Packet-switched networks of switches. A packet carries the coordinate of its destination switch,
+// and every switch on the way picks an output with a routing algorithm instead of a static routing table.
+// Next to the mesh, a global network (ring or tree) carries scalar and control traffic across the chip.
+use std::collections::HashMap;
+
+use dam::{channel::{ChannelElement, Receiver, Sender}, context::Context, dam_macros::context_macro, simulation::ProgramBuilder, structures::Time, types::DAMType};
+use serde::Deserialize;
+
+use crate::{channel_utils::{wait_for_earliest, InputWait}, pcu::PCUData, switch::{self, Credit, CreditLink, DelayModel, Switch, SwitchMode, SwitchMonitor, UnroutedPolicy}, types::{Coordinate, Heading}};
+
+// The express directions are long-range links that skip several switches in one hop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Local, North, East, South, West,
+    ExpressNorth, ExpressEast, ExpressSouth, ExpressWest
+}
+
+impl Direction {
+    pub const ALL: [Direction; 9] = [
+        Direction::Local, Direction::North, Direction::East, Direction::South, Direction::West,
+        Direction::ExpressNorth, Direction::ExpressEast, Direction::ExpressSouth, Direction::ExpressWest
+    ];
+
+    pub fn opposite(&self) -> Direction {
+        match self {
+            Direction::Local => Direction::Local,
+            Direction::North => Direction::South,
+            Direction::East => Direction::West,
+            Direction::South => Direction::North,
+            Direction::West => Direction::East,
+            Direction::ExpressNorth => Direction::ExpressSouth,
+            Direction::ExpressEast => Direction::ExpressWest,
+            Direction::ExpressSouth => Direction::ExpressNorth,
+            Direction::ExpressWest => Direction::ExpressEast
+        }
+    }
+
+    pub fn is_express(&self) -> bool {
+        matches!(self, Direction::ExpressNorth | Direction::ExpressEast | Direction::ExpressSouth | Direction::ExpressWest)
+    }
+
+    pub fn express(&self) -> Direction {
+        match self {
+            Direction::North => Direction::ExpressNorth,
+            Direction::East => Direction::ExpressEast,
+            Direction::South => Direction::ExpressSouth,
+            Direction::West => Direction::ExpressWest,
+            _ => *self
+        }
+    }
+
+    // Position of the port on the edge of the switch, used to derive the delay through the switch.
+    // Express ports share the edge with the nearest-neighbor port of their direction.
+    pub fn offset(&self) -> (i32, i32) {
+        match self {
+            Direction::Local => (0, 0),
+            Direction::North | Direction::ExpressNorth => (0, -1),
+            Direction::East | Direction::ExpressEast => (1, 0),
+            Direction::South | Direction::ExpressSouth => (0, 1),
+            Direction::West | Direction::ExpressWest => (-1, 0)
+        }
+    }
+
+    // The switch this direction leads to from `position`, if the mesh has one. Express links skip to the 
+    // switch `express_stride` switches away, and only exist if the mesh has express links.
+    pub fn neighbor(&self, position: Coordinate, width: usize, height: usize, express_stride: Option<usize>) -> Option<Coordinate> {
+        let distance = match (self.is_express(), express_stride) {
+            (false, _) => 1,
+            (true, Some(stride)) => stride,
+            (true, None) => return None
+        };
+        let (x, y) = position;
+        match self {
+            Direction::Local => None,
+            Direction::North | Direction::ExpressNorth => if y >= distance { Some((x, y - distance)) } else { None },
+            Direction::East | Direction::ExpressEast => if x + distance < width { Some((x + distance, y)) } else { None },
+            Direction::South | Direction::ExpressSouth => if y + distance < height { Some((x, y + distance)) } else { None },
+            Direction::West | Direction::ExpressWest => if x >= distance { Some((x - distance, y)) } else { None }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum RoutingAlgorithm {
+    XY,             // first along x, then along y
+    YX,             // first along y, then along x
+    MinimalAdaptive // any productive direction, preferring the output with the emptier buffer
+}
+
+// Minimal adaptive routing alone can deadlock on cyclic channel dependencies. Packets start out on VC 0 and
+// route adaptively; a packet that finds no productive output with room moves to the escape VC, on which it
+// follows XY routing, which is deadlock-free, until it reaches its destination.
+pub const ESCAPE_VC: usize = 1;
+
+// Packet routing of one switch of a mesh. A packet takes an express link whenever it still has to travel 
+// at least `express_stride` switches in that direction.
+#[derive(Clone, Debug)]
+pub struct PacketRouting {
+    pub algorithm: RoutingAlgorithm,
+    pub position: Coordinate,
+    pub express_stride: Option<usize>,
+    pub inputs: HashMap<Direction, usize>,  // input port of every direction the switch is connected to
+    pub outputs: HashMap<Direction, usize> // output port of every direction the switch is connected to
+}
+
+impl PacketRouting {
+    fn x_direction(&self, dest: Coordinate) -> Option<Direction> {
+        if dest.0 > self.position.0 { Some(Direction::East) }
+        else if dest.0 < self.position.0 { Some(Direction::West) }
+        else { None }
+    }
+
+    fn y_direction(&self, dest: Coordinate) -> Option<Direction> {
+        if dest.1 > self.position.1 { Some(Direction::South) }
+        else if dest.1 < self.position.1 { Some(Direction::North) }
+        else { None }
+    }
+
+    // The express link of a direction, if the switch has one and the packet does not overshoot with it.
+    fn toward(&self, direction: Direction, distance: usize) -> Direction {
+        match self.express_stride {
+            Some(stride) if distance >= stride && self.outputs.contains_key(&direction.express()) => direction.express(),
+            _ => direction
+        }
+    }
+
+    fn output(&self, direction: Direction) -> usize {
+        *self.outputs.get(&direction).expect("Packet routed off the edge of the mesh.")
+    }
+
+    pub fn is_injection_port(&self, input: usize) -> bool {
+        self.inputs.get(&Direction::Local) == Some(&input)
+    }
+
+    pub fn is_ejection_port(&self, output: usize) -> bool {
+        self.outputs.get(&Direction::Local) == Some(&output)
+    }
+
+    // Heading of a packet at this switch.
+    pub fn heading(&self, dest: Coordinate) -> Heading {
+        Heading { east: dest.0 >= self.position.0, south: dest.1 >= self.position.1 }
+    }
+
+    // The headings at this switch of the packets that leave on `output` and arrive at the next switch with 
+    // `heading`. Packets only move towards their destination, so a packet can start heading East by reaching 
+    // its column, or South by reaching its row, but never the other way around. Following the headings 
+    // backwards therefore never runs in a circle, even though the links of the mesh do.
+    pub fn headings_into(&self, output: usize, heading: Heading) -> Vec<Heading> {
+        let direction = self.outputs.iter().find(|(_, port)| **port == output).map(|(d, _)| *d).expect("Output is not part of the mesh.");
+        match direction.offset() {
+            (1, 0) if heading.east => vec![heading],
+            (-1, 0) => vec![Heading { east: false, ..heading }],
+            (0, 1) if heading.south => vec![heading],
+            (0, -1) => vec![Heading { south: false, ..heading }],
+            _ => vec![]
+        }
+    }
+
+    // Returns the output port and the virtual channel a packet continues on.
+    // `has_room(output, vc)` tells whether the buffer of an output virtual channel can take another packet.
+    pub fn route(&self, dest: Coordinate, vc: usize, has_room: &dyn Fn(usize, usize) -> bool) -> (usize, usize) {
+        let x = self.x_direction(dest).map(|d| self.toward(d, dest.0.abs_diff(self.position.0)));
+        let y = self.y_direction(dest).map(|d| self.toward(d, dest.1.abs_diff(self.position.1)));
+        let xy = x.or(y).unwrap_or(Direction::Local);
+        match self.algorithm {
+            RoutingAlgorithm::XY => (self.output(xy), vc),
+            RoutingAlgorithm::YX => (self.output(y.or(x).unwrap_or(Direction::Local)), vc),
+            RoutingAlgorithm::MinimalAdaptive if vc == ESCAPE_VC || xy == Direction::Local => (self.output(xy), vc),
+            RoutingAlgorithm::MinimalAdaptive => {
+                [x, y].iter().flatten()
+                    .map(|direction| self.output(*direction))
+                    .find(|output| has_room(*output, vc))
+                    .map(|output| (output, vc))
+                    .unwrap_or((self.output(xy), ESCAPE_VC))
+            }
+        }
+    }
+}
+
+pub struct MeshConfig {
+    pub width: usize,
+    pub height: usize,
+    pub simd: usize,
+    pub datatype_width: usize,
+    pub input_buffer_depth: usize,
+    pub output_buffer_depth: usize,
+    pub num_vcs: usize,          // MinimalAdaptive needs at least two, one of them the escape VC
+    pub switch_delay: usize,     // cycles through a switch
+    pub wire_delay: usize,       // additional cycles per unit of distance between the input and output port
+    pub channel_capacity: usize,
+    pub algorithm: RoutingAlgorithm,
+    pub express: Option<ExpressConfig>
+}
+
+// Express links connect every switch with the switches `stride` switches away along x and y.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExpressConfig {
+    pub stride: usize,
+    pub latency: usize // cycles on the express link, on top of the delay through the switch
+}
+
+// A width x height mesh of switches. Every switch has a local port, through which packets enter and
+// leave the network, and a port towards each of its neighbors.
+pub struct Mesh {
+    pub switches: Vec<Switch>,           // switches[y * width + x]
+    pub inputs: Vec<Sender<PCUData>>,    // inputs[y * width + x] injects packets at the switch (x, y)
+    pub outputs: Vec<Receiver<PCUData>> // outputs[y * width + x] delivers the packets destined to (x, y)
+}
+
+impl Mesh {
+    pub fn build(parent: &mut ProgramBuilder, config: &MeshConfig) -> Mesh {
+        let positions: Vec<Coordinate> = (0..config.height).flat_map(|y| (0..config.width).map(move |x| (x, y))).collect();
+        let index = |(x, y): Coordinate| y * config.width + x;
+        let express_stride = config.express.map(|express| express.stride);
+        if let Some(stride) = express_stride {
+            assert!(stride > 1, "Express links have to skip at least one switch.");
+        }
+
+        // One channel per direction and switch, in both directions. receivers[switch][direction] feeds the switch.
+        let mut senders: Vec<HashMap<Direction, Sender<PCUData>>> = positions.iter().map(|_| HashMap::new()).collect();
+        let mut receivers: Vec<HashMap<Direction, Receiver<PCUData>>> = positions.iter().map(|_| HashMap::new()).collect();
+        // With several virtual channels, a link between switches carries several of them, so the consumer returns 
+        // credits for it. credit_returns[switch][direction] belongs to an input, credit_links[switch][direction] to an output.
+        let mut credit_returns: Vec<HashMap<Direction, Sender<Credit>>> = positions.iter().map(|_| HashMap::new()).collect();
+        let mut credit_links: Vec<HashMap<Direction, Receiver<Credit>>> = positions.iter().map(|_| HashMap::new()).collect();
+        let mut inputs = vec![];
+        let mut outputs = vec![];
+        for position in positions.iter() {
+            let (inject, local_in) = parent.bounded(config.channel_capacity);
+            let (local_out, eject) = parent.bounded(config.channel_capacity);
+            receivers[index(*position)].insert(Direction::Local, local_in);
+            senders[index(*position)].insert(Direction::Local, local_out);
+            inputs.push(inject);
+            outputs.push(eject);
+
+            for direction in Direction::ALL.iter() {
+                if let Some(neighbor) = direction.neighbor(*position, config.width, config.height, express_stride) {
+                    let (snd, rcv) = parent.bounded(config.channel_capacity);
+                    senders[index(*position)].insert(*direction, snd);
+                    receivers[index(neighbor)].insert(direction.opposite(), rcv);
+                    if config.num_vcs > 1 {
+                        let (credit_snd, credit_rcv) = parent.bounded(config.num_vcs * config.input_buffer_depth);
+                        credit_returns[index(neighbor)].insert(direction.opposite(), credit_snd);
+                        credit_links[index(*position)].insert(*direction, credit_rcv);
+                    }
+                }
+            }
+        }
+
+        let switches = positions.iter().map(|position| {
+            let mut switch_senders = senders[index(*position)].drain().collect::<Vec<_>>();
+            let mut switch_receivers = receivers[index(*position)].drain().collect::<Vec<_>>();
+            let order = |direction: &Direction| Direction::ALL.iter().position(|d| d == direction).unwrap();
+            switch_senders.sort_by_key(|(direction, _)| order(direction));
+            switch_receivers.sort_by_key(|(direction, _)| order(direction));
+
+            let input_directions: Vec<Direction> = switch_receivers.iter().map(|(direction, _)| *direction).collect();
+            let output_directions: Vec<Direction> = switch_senders.iter().map(|(direction, _)| *direction).collect();
+
+            let hw_config = switch::HwConfig {
+                simd: config.simd,
+                datatype_width: config.datatype_width,
+                num_inputs: input_directions.len(),
+                num_outputs: output_directions.len(),
+                mode: SwitchMode::SingleEnqueueSingleDequeue,
+                delay: Mesh::delay_model(config, &input_directions, &output_directions),
+                input_buffer_depth: config.input_buffer_depth,
+                output_buffer_depth: config.output_buffer_depth,
+                num_vcs: config.num_vcs,
+            };
+            let rt_config = switch::RtConfig {
+                packet_routing: Some(PacketRouting {
+                    algorithm: config.algorithm,
+                    position: *position,
+                    express_stride: express_stride,
+                    inputs: input_directions.iter().enumerate().map(|(port, d)| (*d, port)).collect(),
+                    outputs: output_directions.iter().enumerate().map(|(port, d)| (*d, port)).collect()
+                }),
+                ..Default::default()
+            };
+            // Every switch of the mesh has the same input buffers, so a switch stands in for its neighbors.
+            let switch_credit_returns = input_directions.iter().map(|d| credit_returns[index(*position)].remove(d)).collect();
+            let switch_credit_links = output_directions.iter()
+                .map(|d| credit_links[index(*position)].remove(d).map(|credits| CreditLink::new(credits, &hw_config)))
+                .collect();
+            Switch::new(hw_config, rt_config,
+                switch_receivers.into_iter().map(|(_, r)| r).collect(),
+                switch_senders.into_iter().map(|(_, s)| s).collect())
+                .with_credit_ports(switch_credit_returns, switch_credit_links)
+                .with_name(&format!("switch ({}, {})", position.0, position.1))
+        }).collect();
+
+        Mesh { switches: switches, inputs: inputs, outputs: outputs }
+    }
+
+    // The delay grows with the distance between the ports on the edge of the switch. Leaving on an express 
+    // link adds the latency of the link.
+    fn delay_model(config: &MeshConfig, input_directions: &Vec<Direction>, output_directions: &Vec<Direction>) -> DelayModel {
+        let distance = DelayModel::Distance {
+            input_positions: input_directions.iter().map(|d| d.offset()).collect(),
+            output_positions: output_directions.iter().map(|d| d.offset()).collect(),
+            base: config.switch_delay,
+            per_unit: config.wire_delay
+        };
+        match config.express {
+            None => distance,
+            Some(express) => DelayModel::Table((0..input_directions.len()).map(|input| {
+                output_directions.iter().enumerate().map(|(output, direction)| {
+                    distance.delay(input, output) + if direction.is_express() { express.latency } else { 0 }
+                }).collect()
+            }).collect())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum GlobalTopology {
+    Ring,                  // bidirectional ring, a message goes the shorter way around
+    Tree { fanout: usize } // the ports are the leaves, a message goes up to the common ancestor and back down
+}
+
+impl GlobalTopology {
+    pub fn hops(&self, from: usize, to: usize, num_ports: usize) -> usize {
+        match self {
+            GlobalTopology::Ring => {
+                let distance = from.abs_diff(to);
+                std::cmp::min(distance, num_ports - distance)
+            },
+            GlobalTopology::Tree { fanout } => {
+                let (mut from, mut to, mut levels) = (from, to, 0);
+                while from != to {
+                    from /= fanout;
+                    to /= fanout;
+                    levels += 1;
+                }
+                2 * levels
+            }
+        }
+    }
+}
+
+pub struct GlobalNetworkConfig {
+    pub topology: GlobalTopology,
+    pub hop_latency: usize,
+    pub routes: HashMap<usize, Vec<usize>>, // routes[in] -> out, e.g. one enable token to several PCUs
+    pub unrouted: UnroutedPolicy            // handles messages on ports that `routes` does not cover, like a switch
+}
+
+// A chip-wide network for scalar and control traffic (T = Scalar or ControlToken), next to the mesh. 
+// It is statically routed and only modeled by its latency: a message arrives `hops * hop_latency` cycles 
+// after it was sent, at least one cycle later, and every port delivers at most one message per cycle.
+#[context_macro]
+pub struct GlobalNetwork<T: DAMType> {
+    config: GlobalNetworkConfig,
+    inputs: Vec<Receiver<T>>,  // inputs[port]
+    outputs: Vec<Sender<T>>,   // outputs[port]
+    last_delivery: Vec<Option<Time>>,
+    name: String,
+    monitor: SwitchMonitor
+}
+
+impl<T: DAMType> GlobalNetwork<T> {
+    pub fn new(config: GlobalNetworkConfig, inputs: Vec<Receiver<T>>, outputs: Vec<Sender<T>>) -> GlobalNetwork<T> {
+        assert_eq!(inputs.len(), outputs.len(), "Every port of the global network has an input and an output.");
+        for (input, targets) in config.routes.iter() {
+            assert!(*input < inputs.len(), "Global network routes a port that does not exist.");
+            assert!(!targets.is_empty(), "Global network routes a port to no port. Leave it out to use the unrouted policy.");
+            assert!(targets.iter().all(|output| *output < outputs.len()), "Global network routes to a port that does not exist.");
+        }
+        if let UnroutedPolicy::DefaultOutput(output) = config.unrouted {
+            assert!(output < outputs.len(), "Default output does not exist.");
+        }
+        if let GlobalTopology::Tree { fanout } = config.topology {
+            assert!(fanout > 1, "A tree needs a fanout of at least two.");
+        }
+
+        let num_ports = inputs.len();
+        let network = GlobalNetwork {
+            config: config,
+            inputs: inputs,
+            outputs: outputs,
+            last_delivery: vec![None; num_ports],
+            name: String::from("global network"),
+            monitor: SwitchMonitor::default(),
+            context_info: Default::default()
+        };
+        network.inputs.iter().for_each(|r| r.attach_receiver(&network));
+        network.outputs.iter().for_each(|s| s.attach_sender(&network));
+        network
+    }
+
+    // The name the network reports under.
+    pub fn with_name(mut self, name: &str) -> GlobalNetwork<T> {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn with_monitor(mut self, monitor: SwitchMonitor) -> GlobalNetwork<T> {
+        self.monitor = monitor;
+        self
+    }
+
+    pub fn latency(&self, from: usize, to: usize) -> usize {
+        let hops = self.config.topology.hops(from, to, self.inputs.len());
+        std::cmp::max(hops * self.config.hop_latency, 1)
+    }
+}
+
+impl<T: DAMType> Context for GlobalNetwork<T> {
+    fn init(&mut self) {}
+
+    fn run(&mut self) {
+        loop {
+            let ready = match wait_for_earliest(&self.inputs, &self.time) {
+                InputWait::Available(inputs) => inputs,
+                InputWait::Closed => return,
+                InputWait::Waited => continue
+            };
+            for input in ready {
+                let message = self.inputs[input].dequeue(&self.time).unwrap();
+                // The global network has a single channel per port, which it reports as VC 0.
+                let targets = match (self.config.routes.get(&input), self.config.unrouted) {
+                    (Some(targets), _) => targets.clone(),
+                    (None, UnroutedPolicy::DefaultOutput(output)) => vec![output],
+                    (None, unrouted) => {
+                        self.monitor.report_unrouted(unrouted, &self.name, input, 0);
+                        continue;
+                    }
+                };
+                for output in targets.iter() {
+                    let mut arrival = self.time.tick() + self.latency(input, *output) as u64;
+                    if let Some(last) = &self.last_delivery[*output] {
+                        arrival = std::cmp::max(arrival, last.clone() + 1);
+                    }
+                    self.last_delivery[*output] = Some(arrival.clone());
+                    self.outputs[*output].enqueue(&self.time, ChannelElement::new(arrival, message.data.clone())).unwrap();
+                }
+            }
+            self.time.incr_cycles(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use dam::{simulation::{InitializationOptionsBuilder, ProgramBuilder, RunOptions}, structures::Time, utility_contexts::{CheckerContext, GeneratorContext}};
+
+    use crate::{pcu::PCUData, scalar::Scalar, switch::{SwitchError, SwitchErrorKind, SwitchMonitor, UnroutedPolicy}, test_utils::{Collector, TimedChecker, TimedGenerator}, types::{Coordinate, Heading}};
+
+    use super::{Direction, ExpressConfig, GlobalNetwork, GlobalNetworkConfig, GlobalTopology, Mesh, MeshConfig, PacketRouting, RoutingAlgorithm, ESCAPE_VC};
+
+    fn center_switch(algorithm: RoutingAlgorithm) -> PacketRouting {
+        let ports: HashMap<Direction, usize> = Direction::ALL.iter().enumerate().map(|(port, d)| (*d, port)).collect();
+        PacketRouting { 
+            algorithm: algorithm, 
+            position: (1, 1), 
+            express_stride: None, 
+            inputs: ports.clone(), 
+            outputs: ports
+        }
+    }
+
+    #[test]
+    fn routing_algorithms_test() {
+        let port = |d: Direction| Direction::ALL.iter().position(|x| *x == d).unwrap();
+        let dest: Coordinate = (2, 0);
+        let always = |_: usize, _: usize| true;
+
+        assert_eq!(center_switch(RoutingAlgorithm::XY).route(dest, 0, &always), (port(Direction::East), 0));
+        assert_eq!(center_switch(RoutingAlgorithm::YX).route(dest, 0, &always), (port(Direction::North), 0));
+        assert_eq!(center_switch(RoutingAlgorithm::XY).route((1, 1), 0, &always), (port(Direction::Local), 0));
+
+        // The adaptive router avoids the full East output, and escapes once both productive outputs are full.
+        let adaptive = center_switch(RoutingAlgorithm::MinimalAdaptive);
+        let east_full = |output: usize, _: usize| output != port(Direction::East);
+        assert_eq!(adaptive.route(dest, 0, &east_full), (port(Direction::North), 0));
+        assert_eq!(adaptive.route(dest, 0, &|_, _| false), (port(Direction::East), ESCAPE_VC));
+        assert_eq!(adaptive.route(dest, ESCAPE_VC, &east_full), (port(Direction::East), ESCAPE_VC));
+    }
+
+    #[test]
+    fn headings_test() {
+        let port = |d: Direction| Direction::ALL.iter().position(|x| *x == d).unwrap();
+        let switch = center_switch(RoutingAlgorithm::XY);
+        let (north_east, north_west, south_east) = (Heading { east: true, south: false }, Heading { east: false, south: false }, Heading { east: true, south: true });
+
+        assert_eq!(switch.heading((2, 0)), north_east);
+        assert_eq!(switch.heading((1, 1)), south_east);
+        // Going East, a packet keeps heading East; going West, it may reach its column and turn East.
+        assert_eq!(switch.headings_into(port(Direction::East), north_east), vec![north_east]);
+        assert_eq!(switch.headings_into(port(Direction::East), north_west), vec![]);
+        assert_eq!(switch.headings_into(port(Direction::ExpressWest), north_east), vec![north_west]);
+        assert_eq!(switch.headings_into(port(Direction::North), south_east), vec![north_east]);
+        assert_eq!(switch.headings_into(port(Direction::South), north_east), vec![]);
+    }
+
+    #[test]
+    fn mesh_test() {
+        let mut parent = ProgramBuilder::default();
+        const NUM_ELEMENTS: u64 = 10;
+
+        let config = MeshConfig {
+            width: 2,
+            height: 2,
+            simd: 1,
+            datatype_width: Scalar::I32(0).width(),
+            input_buffer_depth: 4,
+            output_buffer_depth: 4,
+            num_vcs: 1,
+            switch_delay: 1,
+            wire_delay: 0,
+            channel_capacity: 8,
+            algorithm: RoutingAlgorithm::XY,
+            express: None
+        };
+        let mesh = Mesh::build(&mut parent, &config);
+
+        // (0, 0) sends to (1, 1) on even cycles and (1, 1) to (0, 0) on odd cycles, so the two streams never 
+        // compete for a switch. Every switch on the way takes one cycle: (0, 0) -> (1, 0) -> (1, 1) -> out, 
+        // and (1, 1) -> (0, 1) -> (0, 0) -> out. The other switches stay idle.
+        let packet = |x: u64, dest: Coordinate| PCUData::new(vec![Scalar::I32(x as i32)]).with_destination(dest);
+        let sent = |dest: Coordinate, first: u64| (0..NUM_ELEMENTS).map(|x| (first + 2 * x, packet(x, dest))).collect::<Vec<_>>();
+        let received = |dest: Coordinate| (0..NUM_ELEMENTS).map(|x| packet(x, dest)).collect::<Vec<_>>();
+        let mut arrivals = vec![];
+        for (index, (input, output)) in mesh.inputs.into_iter().zip(mesh.outputs.into_iter()).enumerate() {
+            let (sent, received) = match index {
+                0 => (sent((1, 1), 0), received((0, 0))),
+                3 => (sent((0, 0), 1), received((1, 1))),
+                _ => (vec![], vec![])
+            };
+            parent.add_child(TimedGenerator::new(sent, input));
+            let checker = TimedChecker::new(received, output);
+            arrivals.push(checker.arrivals());
+            parent.add_child(checker);
+        }
+        mesh.switches.into_iter().for_each(|switch| parent.add_child(switch));
+
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+        let expected = |first: u64| (0..NUM_ELEMENTS).map(|x| Time::new(first + 2 * x)).collect::<Vec<_>>();
+        assert_eq!(*arrivals[3].lock().unwrap(), expected(3));
+        assert_eq!(*arrivals[0].lock().unwrap(), expected(4));
+    }
+
+    #[test]
+    fn adaptive_mesh_test() {
+        let mut parent = ProgramBuilder::default();
+        const NUM_ELEMENTS: usize = 20;
+
+        let config = MeshConfig {
+            width: 2,
+            height: 2,
+            simd: 1,
+            datatype_width: Scalar::I32(0).width(),
+            input_buffer_depth: 2,
+            output_buffer_depth: 1,
+            num_vcs: 2,
+            switch_delay: 1,
+            wire_delay: 0,
+            channel_capacity: 8,
+            algorithm: RoutingAlgorithm::MinimalAdaptive,
+            express: None
+        };
+        let mesh = Mesh::build(&mut parent, &config);
+
+        // Three switches send to (1, 1), which only ejects one packet per cycle. The links into (1, 1) back up 
+        // until (0, 0) finds both its productive outputs full, and moves packets to the escape VC.
+        let packet = |source: usize| PCUData::new(vec![Scalar::I32(source as i32)]).with_destination((1, 1));
+        let mut received = None;
+        for (index, (input, output)) in mesh.inputs.into_iter().zip(mesh.outputs.into_iter()).enumerate() {
+            if index == 3 {
+                parent.add_child(TimedGenerator::new(vec![], input));
+                let collector = Collector::new(output);
+                received = Some(collector.received());
+                parent.add_child(collector);
+            } else {
+                parent.add_child(TimedGenerator::new((0..NUM_ELEMENTS).map(|x| (x as u64, packet(index))).collect(), input));
+                parent.add_child(TimedChecker::new(vec![], output));
+            }
+        }
+        mesh.switches.into_iter().for_each(|switch| parent.add_child(switch));
+
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+
+        let received = received.unwrap();
+        let received = received.lock().unwrap();
+        for source in 0..3 {
+            assert_eq!(received.iter().filter(|(_, data)| data.data == packet(source).data).count(), NUM_ELEMENTS);
+        }
+        assert_eq!(received.len(), 3 * NUM_ELEMENTS);
+        assert!(received.iter().any(|(_, data)| data.vc == ESCAPE_VC));
+    }
+
+    #[test]
+    fn express_mesh_test() {
+        const NUM_ELEMENTS: u64 = 10;
+        const SWITCH_DELAY: u64 = 1;
+        const EXPRESS_LATENCY: u64 = 2;
+
+        // A 4x1 mesh. (0, 0) sends to (3, 0) every cycle and the packets are checked as they leave (3, 0).
+        let run = |express: Option<ExpressConfig>| {
+            let mut parent = ProgramBuilder::default();
+            let config = MeshConfig {
+                width: 4,
+                height: 1,
+                simd: 1,
+                datatype_width: Scalar::I32(0).width(),
+                input_buffer_depth: 4,
+                output_buffer_depth: 4,
+                num_vcs: 1,
+                switch_delay: SWITCH_DELAY as usize,
+                wire_delay: 0,
+                channel_capacity: 8,
+                algorithm: RoutingAlgorithm::XY,
+                express: express
+            };
+            let mesh = Mesh::build(&mut parent, &config);
+
+            let packet = |x: u64| PCUData::new(vec![Scalar::I32(x as i32)]).with_destination((3, 0));
+            let mut arrivals = None;
+            for (index, (input, output)) in mesh.inputs.into_iter().zip(mesh.outputs.into_iter()).enumerate() {
+                let sent = if index == 0 { (0..NUM_ELEMENTS).map(|x| (x, packet(x))).collect() } else { vec![] };
+                let received = if index == 3 { (0..NUM_ELEMENTS).map(packet).collect() } else { vec![] };
+                parent.add_child(TimedGenerator::new(sent, input));
+                let checker = TimedChecker::new(received, output);
+                if index == 3 {
+                    arrivals = Some(checker.arrivals());
+                }
+                parent.add_child(checker);
+            }
+            mesh.switches.into_iter().for_each(|switch| parent.add_child(switch));
+
+            let executed = parent
+                .initialize(InitializationOptionsBuilder::default().build().unwrap())
+                .unwrap()
+                .run(RunOptions::default());
+            assert!(executed.passed());
+            arrivals.unwrap().lock().unwrap().clone()
+        };
+        let expected = |latency: u64| (0..NUM_ELEMENTS).map(|x| Time::new(x + latency)).collect::<Vec<_>>();
+
+        // Without express links, a packet goes through all four switches: (0, 0) -> (1, 0) -> (2, 0) -> (3, 0) -> out.
+        assert_eq!(run(None), expected(4 * SWITCH_DELAY));
+        // With them, it skips (1, 0): (0, 0) -> (2, 0) -> (3, 0) -> out, but pays for the express link.
+        assert_eq!(run(Some(ExpressConfig { stride: 2, latency: EXPRESS_LATENCY as usize })), expected(3 * SWITCH_DELAY + EXPRESS_LATENCY));
+    }
+
+    #[test]
+    fn global_network_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_PORTS: usize = 4;
+        const NUM_ELEMENTS: i32 = 10;
+        const HOP_LATENCY: usize = 2;
+
+        assert_eq!(GlobalTopology::Ring.hops(0, 3, NUM_PORTS), 1);
+        assert_eq!(GlobalTopology::Tree { fanout: 2 }.hops(0, 1, NUM_PORTS), 2);
+        assert_eq!(GlobalTopology::Tree { fanout: 2 }.hops(0, 3, NUM_PORTS), 4);
+
+        // Port 0 sends scalars halfway around the ring.
+        let (senders, inputs): (Vec<_>, Vec<_>) = (0..NUM_PORTS).map(|_| parent.bounded(CHAN_SIZE)).unzip();
+        let (outputs, receivers): (Vec<_>, Vec<_>) = (0..NUM_PORTS).map(|_| parent.bounded(CHAN_SIZE)).unzip();
+        let config = GlobalNetworkConfig { 
+            topology: GlobalTopology::Ring, 
+            hop_latency: HOP_LATENCY, 
+            routes: [(0, vec![2])].into_iter().collect(),
+            unrouted: UnroutedPolicy::Error
+        };
+        parent.add_child(GlobalNetwork::new(config, inputs, outputs));
+
+        let scalars = || (0..NUM_ELEMENTS).map(|x| Scalar::I32(x));
+        for (port, (sender, receiver)) in senders.into_iter().zip(receivers.into_iter()).enumerate() {
+            if port == 0 {
+                parent.add_child(GeneratorContext::new(scalars, sender));
+            } else {
+                parent.add_child(GeneratorContext::new(|| std::iter::empty(), sender));
+            }
+            if port == 2 {
+                parent.add_child(CheckerContext::new(scalars, receiver));
+            } else {
+                parent.add_child(CheckerContext::new(|| std::iter::empty(), receiver));
+            }
+        }
+
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+        assert_eq!(executed.elapsed_cycles().unwrap(), NUM_ELEMENTS as u64 + 2 * HOP_LATENCY as u64);
+    }
+
+    #[test]
+    fn global_network_unrouted_test() {
+        const CHAN_SIZE: usize = 8;
+        const NUM_PORTS: usize = 4;
+
+        // Only port 0 is routed, but port 1 sends a scalar.
+        let run = |unrouted: UnroutedPolicy| {
+            let mut parent = ProgramBuilder::default();
+            let (senders, inputs): (Vec<_>, Vec<_>) = (0..NUM_PORTS).map(|_| parent.bounded(CHAN_SIZE)).unzip();
+            let (outputs, receivers): (Vec<_>, Vec<_>) = (0..NUM_PORTS).map(|_| parent.bounded(CHAN_SIZE)).unzip();
+            let config = GlobalNetworkConfig { 
+                topology: GlobalTopology::Ring, 
+                hop_latency: 1, 
+                routes: [(0, vec![2])].into_iter().collect(),
+                unrouted: unrouted
+            };
+            let monitor = SwitchMonitor::default();
+            parent.add_child(GlobalNetwork::new(config, inputs, outputs).with_name("global").with_monitor(monitor.clone()));
+
+            for (port, (sender, receiver)) in senders.into_iter().zip(receivers.into_iter()).enumerate() {
+                if port == 1 {
+                    parent.add_child(GeneratorContext::new(|| std::iter::once(Scalar::I32(-1)), sender));
+                } else {
+                    parent.add_child(GeneratorContext::new(|| std::iter::empty(), sender));
+                }
+                if port == 3 && unrouted == UnroutedPolicy::DefaultOutput(3) {
+                    parent.add_child(CheckerContext::new(|| std::iter::once(Scalar::I32(-1)), receiver));
+                } else {
+                    parent.add_child(CheckerContext::new(|| std::iter::empty(), receiver));
+                }
+            }
+
+            let executed = parent
+                .initialize(InitializationOptionsBuilder::default().build().unwrap())
+                .unwrap()
+                .run(RunOptions::default());
+            (executed.passed(), monitor)
+        };
+
+        let (passed, monitor) = run(UnroutedPolicy::DefaultOutput(3));
+        assert!(passed);
+        assert!(monitor.errors().is_empty());
+
+        let (passed, monitor) = run(UnroutedPolicy::Drop);
+        assert!(passed);
+        assert_eq!(monitor.dropped("global"), 1);
+
+        // An error stops the network, so the run does not pass.
+        let (passed, monitor) = run(UnroutedPolicy::Error);
+        assert!(!passed);
+        assert_eq!(monitor.errors(), vec![SwitchError { switch: String::from("global"), port: 1, kind: SwitchErrorKind::UnroutedInput { vc: 0 } }]);
+    }
+}
@@ -1,11 +1,17 @@
 mod alu;
+mod channel_utils;
+mod control;
+mod counter;
 mod pcu; 
 mod pmu; 
 mod interconnect;
 mod scalar;
 mod pipeline_stage;
 mod switch;
+mod types;
 mod hop_lower;
+#[cfg(test)]
+mod test_utils;
 
 fn main() {
     println!("Hello, world!");
@@ -16,7 +22,7 @@ mod tests {
     use std::collections::HashSet;
 
     use dam::{simulation::{InitializationOptionsBuilder, ProgramBuilder, RunOptions}, utility_contexts::{CheckerContext, GeneratorContext}};
-    use crate::{alu::{ALUHwConfig, ALUInput, ALUOp, ALURtConfig}, pcu::{self, PCUData}, scalar::Scalar, switch};
+    use crate::{alu::{ALUHwConfig, ALUInput, ALUOp, ALURtConfig, ALUTimingTable}, pcu::{self, PCUData}, scalar::Scalar, switch};
 
     #[test]
     fn switch_and_pcu_test() {
@@ -32,46 +38,51 @@ mod tests {
         let (in_send_3, in_3) = parent.bounded(CHAN_SIZE);
 
         let gen0 = GeneratorContext::new(
-            || {0..NUM_ELEMENTS}.map(|x| PCUData { data: vec![Scalar::I32(x   )]}), in_send_0);
+            || {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x   )])), in_send_0);
         let gen1 = GeneratorContext::new(
-            || {0..NUM_ELEMENTS}.map(|x| PCUData { data: vec![Scalar::I32(x+10)]}), in_send_1);
+            || {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x+10)])), in_send_1);
         let gen2 = GeneratorContext::new(
-            || {0..NUM_ELEMENTS}.map(|x| PCUData { data: vec![Scalar::I32(x+20)]}), in_send_2);
+            || {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x+20)])), in_send_2);
         let gen3 = GeneratorContext::new(
-            || {0..NUM_ELEMENTS}.map(|x| PCUData { data: vec![Scalar::I32(x+30)]}), in_send_3);
+            || {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x+30)])), in_send_3);
     
         let (pcu_3_out, checker_in) = parent.bounded(CHAN_SIZE);
 
         // Out := a*b + c*d
         let checker = CheckerContext::new(|| 
-            {(0..NUM_ELEMENTS).map(|i|  PCUData{data: vec![
+            {(0..NUM_ELEMENTS).map(|i|  PCUData::new(vec![
                 Scalar::I32((i)*(10+i) + (20+i)*(30+i))
-            ]})
+            ]))
             }, checker_in);
 
         parent.add_child(gen0); parent.add_child(gen1); parent.add_child(gen2); parent.add_child(gen3);
         parent.add_child(checker);
 
         let pcu_hw_config = pcu::HwConfig {
-            alu_configs: vec![ALUHwConfig {
-                supported_ops: HashSet::from([ALUOp::ADD_I32, ALUOp::MUL_I32])
-            };1],
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32, ALUOp::MUL_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
             num_vector_input_ports: 2,
-            num_simd_lanes: 1
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            ..Default::default()
         };
 
         let pcu_rt_config_1 = pcu::RtConfig {
-            alu_configs: vec![
+            alu_configs: vec![vec![
                 ALURtConfig {op:ALUOp::MUL_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::PREV(1), target: 0 }
-                ]
+                ]],
+            ..Default::default()
         };
 
         let pcu_rt_config_2 = pcu_rt_config_1.clone();
 
         let pcu_rt_config_3 = pcu::RtConfig {
-            alu_configs: vec![
+            alu_configs: vec![vec![
                 ALURtConfig {op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::PREV(1), target: 0 }
-                ]
+                ]],
+            ..Default::default()
         };
 
 
@@ -84,19 +95,23 @@ mod tests {
         let pcu_2 = pcu::PCU::new(pcu_hw_config.clone(), pcu_rt_config_2, vec![in_2, in_3], vec![pcu_2_out]);
         let pcu_3 = pcu::PCU::new(pcu_hw_config.clone(), pcu_rt_config_3, vec![pcu_3_in_0, pcu_3_in_1], vec![pcu_3_out]);
 
-        fn switch_delay(_: usize, _: usize) -> usize { SWITCH_DELAY }
-
         let switch_hw_config = switch::HwConfig {
             simd: 1,
             datatype_width: Scalar::I32(0).width(),
             num_inputs: 2,
             num_outputs: 2,
             mode: switch::SwitchMode::SingleEnqueueSingleDequeue,
-            delay: switch_delay,
+            delay: switch::DelayModel::Constant(SWITCH_DELAY),
+            input_buffer_depth: CHAN_SIZE,
+            output_buffer_depth: 1,
+            num_vcs: 1,
         };
 
+        switch_hw_config.validate_producer(pcu_hw_config.num_simd_lanes, Scalar::I32(0).width());
+
         let switch_rt_config = switch::RtConfig {
-            routing_table: [(0, vec![0]), (1, vec![1])].into_iter().collect()
+            routing_table: [(0, vec![0]), (1, vec![1])].into_iter().collect(),
+            ..Default::default()
         };
 
         let switch = switch::Switch::new(
@@ -118,8 +133,8 @@ mod tests {
         assert_eq!(
             executed.elapsed_cycles().unwrap(), 
             NUM_ELEMENTS as u64 * 2
-                + ALUOp::ADD_I32.delay() as u64
-                + ALUOp::MUL_I32.delay() as u64
+                + ALUOp::ADD_I32.default_timing().latency as u64
+                + ALUOp::MUL_I32.default_timing().latency as u64
                 + SWITCH_DELAY as u64);
     }
 }
\ No newline at end of file
@@ -1,36 +1,196 @@
-use dam::{channel::{ChannelElement, Receiver, Sender}, context::Context, dam_macros::context_macro, structures::Time, types::DAMType};
+use std::collections::{HashMap, HashSet};
 
-use crate::{alu::{ALUHwConfig, ALURtConfig}, pipeline_stage::PipelineStage, scalar::Scalar};
+use dam::{channel::{ChannelElement, PeekResult, Receiver, Sender}, context::Context, dam_macros::context_macro, structures::Time, types::DAMType};
 
-#[derive(Clone)]
+use crate::{alu::{ALUHwConfig, ALUInput, ALUOp, ALURtConfig, ALUTimingTable}, control::{poll_config_load, reconfigured_at, ConfigLoad, ControlPort}, counter::{CounterChain, CounterConfig}, pipeline_stage::{Permutation, PipelineRegisters, PipelineStage}, scalar::Scalar, types::{Coordinate, Heading}};
+
+#[derive(Clone, Default)]
 pub struct HwConfig {
-    pub alu_configs: Vec<ALUHwConfig>, // alu_configs[row][column]
+    pub alu_configs: Vec<Vec<ALUHwConfig>>, // alu_configs[stage][alu]
     pub num_simd_lanes: usize,
-    // pub num_registers_per_stage: usize,
-    // pub num_scalar_inputs: usize,
-    // pub num_scalar_outputs: usize,
-    pub num_vector_input_ports: usize
+    pub num_registers_per_stage: usize,
+    pub num_scalar_inputs: usize,
+    pub num_scalar_outputs: usize,
+    pub num_control_ports: usize,
+    pub num_counters: usize,
+    pub num_vector_input_ports: usize,
+    pub shuffle_network: Option<ShuffleHwConfig>, // cross-lane network in front of the ALUs of every stage
+    pub scalar_reduction_timing: ALUTimingTable   // timing of the ALUs in the reduction tree of the scalar outputs
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Default)]
 pub struct RtConfig {
-    pub alu_configs: Vec<ALURtConfig>, // alu_configs[row][column]
+    pub alu_configs: Vec<Vec<ALURtConfig>>, // alu_configs[stage][alu]
+    pub shuffles: HashMap<usize, Permutation>, // shuffles[stage] -> permutation applied to the stage's inputs
+    pub vector_inputs: Vec<InputMode>, // vector_inputs[port], if empty every port read by an ALU is consumed
+    pub scalar_inputs: Vec<InputMode>, // scalar_inputs[port], if empty every port read by an ALU is consumed
+    pub vector_outputs: Vec<VectorOutputConfig>, // vector_outputs[port], if empty port i emits register i of the last stage
+    pub scalar_outputs: Vec<ScalarOutputConfig>, // scalar_outputs[port]
+    pub control: Option<ControlRtConfig>, // if set, iterations are enabled by tokens on the control network
+    pub counters: Vec<CounterConfig>, // counter chain, counters[0] is the outermost loop
+    pub flush: FlushPolicy, // what happens once an input stream or the counter chain ends
+}
+
+// End of stream: once an input closes (or the counter chain and the control network are done), no new 
+// element is issued. The elements in flight finish, and the outputs close once the PCU is done.
+// With EmitState, the registers holding state (those read through NEXT, e.g. accumulators) are only emitted 
+// once, after the last element finished, instead of after every iteration.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FlushPolicy {
+    #[default]
+    Drain,
+    EmitState
+}
+
+// Token-based enable: a batch of `iterations` iterations fires once every `enable` port received a token.
+// After the last iteration of a batch, a done token is sent on every `done` port.
+// With a counter chain, a batch is one pass over the chain instead, and `iterations` is ignored.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ControlRtConfig {
+    pub enable: Vec<usize>,
+    pub done: Vec<usize>,
+    pub iterations: usize
+}
+
+// How an input port is read in every iteration. A consumed port delivers a new value per iteration. 
+// A sticky port holds on to its value: only the first value is waited for, afterwards a newer value 
+// replaces it whenever one has arrived. Only scalar ports can be sticky.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputMode {
+    Unused,
+    Consumed,
+    Sticky
+}
+
+// A vector output port emits a register of any stage, as soon as that stage finished the element.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VectorOutputConfig {
+    pub stage: usize,
+    pub register: usize
+}
+
+// A scalar output port emits the first active lane of a register, or the reduction of all active lanes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScalarOutputConfig {
+    pub stage: usize,
+    pub register: usize,
+    pub reduction: Option<ALUOp>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShuffleHwConfig {
+    pub latency: usize // cycles the shuffle network adds in front of the ALUs of a stage
+}
+
+impl RtConfig {
+    // Vector input ports read by any stage. Later stages see an input port through the pass-through 
+    // registers, as long as no earlier stage overwrote the register.
+    pub fn read_vector_inputs(&self) -> HashSet<usize> {
+        let mut read = HashSet::new();
+        let mut overwritten = HashSet::new();
+        for stage in self.alu_configs.iter() {
+            for cfg in stage.iter() {
+                read.extend(cfg.get_prev_regs().into_iter().filter(|reg| !overwritten.contains(reg)));
+            }
+            overwritten.extend(stage.iter().map(|cfg| cfg.target));
+        }
+        read
+    }
+
+    pub fn read_scalar_inputs(&self) -> HashSet<usize> {
+        self.alu_configs.iter().flatten().flat_map(|cfg| cfg.get_scalar_inputs()).collect()
+    }
+
+    fn input_modes(configured: &Vec<InputMode>, read: HashSet<usize>, num_ports: usize) -> Vec<InputMode> {
+        if !configured.is_empty() {
+            return configured.clone();
+        }
+        (0..num_ports).map(|port| if read.contains(&port) { InputMode::Consumed } else { InputMode::Unused }).collect()
+    }
+
+    // Reduces the lanes of input 0 into lane 0.
+    pub fn vector_sum(add: ALUOp, num_simd_lanes: usize, padding: Scalar) -> RtConfig {
+        RtConfig { alu_configs: reduction_tree(add, 0, num_simd_lanes, padding), ..Default::default() }
+    }
+
+    // Multiplies inputs 0 and 1 lane by lane and reduces the products into lane 0.
+    pub fn dot_product(mul: ALUOp, add: ALUOp, num_simd_lanes: usize, padding: Scalar) -> RtConfig {
+        let mut alu_configs = vec![vec![ALURtConfig { op: mul, in_a: ALUInput::PREV(0), in_b: ALUInput::PREV(1), target: 0 }]];
+        alu_configs.extend(reduction_tree(add, 0, num_simd_lanes, padding));
+        RtConfig { alu_configs: alu_configs, ..Default::default() }
+    }
+}
+
+// Stages of a reduction tree over the lanes of `register`. Stage k combines lane i with lane i + 2^k,
+// so after ceil(log2(num_simd_lanes)) stages, lane 0 holds the reduction of all lanes. 
+// Lanes past the last one read `padding`, which should be the neutral element of `op`. A single lane still 
+// gets a stage, which only combines it with the padding, so that the result has a stage to leave from.
+pub fn reduction_tree(op: ALUOp, register: usize, num_simd_lanes: usize, padding: Scalar) -> Vec<Vec<ALURtConfig>> {
+    let num_stages = std::cmp::max(num_simd_lanes.next_power_of_two().trailing_zeros() as usize, 1);
+    (0..num_stages).map(|k| vec![ALURtConfig {
+        op: op,
+        in_a: ALUInput::PREV(register),
+        in_b: ALUInput::PREV_STRIDE(register, 1 << k, padding),
+        target: register
+    }]).collect()
 }
 
 pub struct PCURuntimeData {
     pipeline_stages: Vec<PipelineStage>,
     input: Vec<Receiver<PCUData>>,
-    output: Vec<Sender<PCUData>>
+    output: Vec<Sender<PCUData>>,
+    scalar_input: Vec<Receiver<Scalar>>,
+    scalar_output: Vec<Sender<Scalar>>,
+    control_ports: Vec<ControlPort>,
+    config_port: Option<Receiver<ConfigLoad<RtConfig>>>,
+    counter_chain: Option<CounterChain>,
+    vector_input_modes: Vec<InputMode>,
+    scalar_input_modes: Vec<InputMode>,
+    sticky_scalars: Vec<Option<Scalar>> // last value of every sticky scalar input
 }
 
+// A vector with one valid bit per lane. Masked-off lanes hold no meaningful value, e.g. the lanes past 
+// the end of a loop whose trip count is not a multiple of the SIMD width.
+// On a packet-switched network, the vector additionally carries its destination and virtual channel.
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct PCUData {
-    pub data: Vec<Scalar>
+    pub data: Vec<Scalar>,
+    pub mask: Vec<bool>,
+    pub dest: Option<Coordinate>,
+    pub vc: usize,
+    pub end_of_stream: Option<Heading> // marker without data: no more packets with this heading come over the link
+}
+
+impl PCUData {
+    pub fn new(data: Vec<Scalar>) -> PCUData {
+        let mask = vec![true; data.len()];
+        PCUData::masked(data, mask)
+    }
+
+    pub fn masked(data: Vec<Scalar>, mask: Vec<bool>) -> PCUData {
+        assert_eq!(data.len(), mask.len(), "The mask needs one bit per lane.");
+        PCUData { data: data, mask: mask, dest: None, vc: 0, end_of_stream: None }
+    }
+
+    pub fn end_of_stream_marker(heading: Heading) -> PCUData {
+        PCUData { end_of_stream: Some(heading), ..Default::default() }
+    }
+
+    pub fn with_destination(mut self, dest: Coordinate) -> PCUData {
+        self.dest = Some(dest);
+        self
+    }
+
+    pub fn with_vc(mut self, vc: usize) -> PCUData {
+        self.vc = vc;
+        self
+    }
 }
 
+// Size in bits of the lanes on the wire.
 impl DAMType for PCUData {
     fn dam_size(&self) -> usize {
-        std::mem::size_of_val(&self.data)
+        self.data.iter().map(|lane| lane.dam_size()).sum()
     }
 }
 
@@ -43,64 +203,433 @@ pub struct PCU {
 
 impl PCU {
     pub fn new(hw_cfg: HwConfig, rt_cfg: RtConfig, input: Vec<Receiver<PCUData>>, output: Vec<Sender<PCUData>>) -> PCU {
-        PCU::verify_alu_ops(&hw_cfg.alu_configs, &rt_cfg.alu_configs);
-
-        let rt_data = PCURuntimeData {
-            pipeline_stages: rt_cfg.alu_configs.iter().map(
-                |cfg| {PipelineStage::new(cfg.clone(), hw_cfg.num_simd_lanes, 1)}).collect(),
-            input: input,
-            output: output
-            
-        };
-
-        let pcu = PCU {
+        let mut pcu = PCU {
             hw_config: hw_cfg,
-            rt_config: rt_cfg,
-            rt_data: rt_data,
+            rt_config: RtConfig::default(),
+            rt_data: PCURuntimeData {
+                pipeline_stages: vec![],
+                input: input,
+                output: output,
+                scalar_input: vec![],
+                scalar_output: vec![],
+                control_ports: vec![],
+                config_port: None,
+                counter_chain: None,
+                vector_input_modes: vec![],
+                scalar_input_modes: vec![],
+                sticky_scalars: vec![]
+            },
             context_info: Default::default()
         };
+        pcu.configure(rt_cfg);
         pcu.rt_data.input .iter().for_each(|i| i.attach_receiver(&pcu));
         pcu.rt_data.output.iter().for_each(|i| i.attach_sender(&pcu));
         pcu
     }
 
-    fn verify_alu_ops(hw_alus: &Vec<ALUHwConfig>, rt_alus: &Vec<ALURtConfig>) -> () {
+    // Checks a runtime configuration against the hardware and the connected ports, and rebuilds the 
+    // runtime state that depends on it.
+    fn configure(&mut self, rt_cfg: RtConfig) {
+        let hw_cfg = &self.hw_config;
+        assert!(!rt_cfg.alu_configs.is_empty(), "A PCU config needs at least one pipeline stage.");
+        PCU::verify_alu_ops(&hw_cfg.alu_configs, &rt_cfg.alu_configs);
+        PCU::verify_registers(hw_cfg, &rt_cfg.alu_configs);
+        PCU::verify_shuffles(hw_cfg, &rt_cfg);
+        PCU::verify_scalar_ports(hw_cfg, &rt_cfg);
+        PCU::verify_control(hw_cfg, &rt_cfg);
+        PCU::verify_counters(hw_cfg, &rt_cfg);
+        PCU::verify_vector_outputs(hw_cfg, &rt_cfg, self.rt_data.output.len());
+        PCU::verify_inputs(hw_cfg, &rt_cfg, self.rt_data.input.len());
+
+        self.rt_data.pipeline_stages = hw_cfg.alu_configs.iter().zip(rt_cfg.alu_configs.iter()).map(
+            |(hw_stage, rt_stage)| {
+                let timings = hw_stage.iter().zip(rt_stage.iter()).map(|(hw, cfg)| hw.timing.get(cfg.op)).collect();
+                PipelineStage::new(rt_stage.clone(), timings, hw_cfg.num_simd_lanes, hw_cfg.num_registers_per_stage)
+            }).enumerate().map(|(idx, stage)| {
+                match (rt_cfg.shuffles.get(&idx), hw_cfg.shuffle_network) {
+                    (Some(permutation), Some(network)) => stage.with_shuffle(permutation.clone(), network.latency),
+                    _ => stage
+                }
+            }).collect();
+        self.rt_data.counter_chain = if rt_cfg.counters.is_empty() { None } else { Some(CounterChain::new(rt_cfg.counters.clone())) };
+        self.rt_data.vector_input_modes = RtConfig::input_modes(&rt_cfg.vector_inputs, rt_cfg.read_vector_inputs(), hw_cfg.num_vector_input_ports);
+        self.rt_data.scalar_input_modes = RtConfig::input_modes(&rt_cfg.scalar_inputs, rt_cfg.read_scalar_inputs(), hw_cfg.num_scalar_inputs);
+        self.rt_data.sticky_scalars = vec![None; hw_cfg.num_scalar_inputs];
+        self.verify_scalar_inputs_read();
+        if !self.rt_data.control_ports.is_empty() {
+            PCU::verify_control_ports(&rt_cfg, &self.rt_data.control_ports);
+        }
+        self.rt_config = rt_cfg;
+    }
+
+    // Once the scalar ports are connected, every one of them has to be read by the current configuration.
+    fn verify_scalar_inputs_read(&self) {
+        if !self.rt_data.scalar_input.is_empty() {
+            assert!(self.rt_data.scalar_input_modes.iter().all(|mode| *mode != InputMode::Unused), 
+                "Connected scalar input is never read.");
+        }
+    }
+
+    pub fn with_scalar_ports(mut self, scalar_input: Vec<Receiver<Scalar>>, scalar_output: Vec<Sender<Scalar>>) -> PCU {
+        assert_eq!(scalar_input.len(), self.hw_config.num_scalar_inputs);
+        assert_eq!(scalar_output.len(), self.hw_config.num_scalar_outputs);
+        self.rt_data.scalar_input = scalar_input;
+        self.rt_data.scalar_output = scalar_output;
+        self.verify_scalar_inputs_read();
+        self.rt_data.scalar_input .iter().for_each(|i| i.attach_receiver(&self));
+        self.rt_data.scalar_output.iter().for_each(|o| o.attach_sender(&self));
+        self
+    }
+
+    pub fn with_control_ports(mut self, control_ports: Vec<ControlPort>) -> PCU {
+        assert_eq!(control_ports.len(), self.hw_config.num_control_ports);
+        PCU::verify_control_ports(&self.rt_config, &control_ports);
+        self.rt_data.control_ports = control_ports;
+        self.rt_data.control_ports.iter().for_each(|p| p.attach(&self));
+        self
+    }
+
+    // Config loads on this port replace the runtime configuration while the PCU runs.
+    pub fn with_config_port(mut self, config_port: Receiver<ConfigLoad<RtConfig>>) -> PCU {
+        config_port.attach_receiver(&self);
+        self.rt_data.config_port = Some(config_port);
+        self
+    }
+
+    // The ports a configuration relies on are connected by the builders after `new`, so this is checked 
+    // once the PCU is about to run, and again for every config load.
+    fn verify_connections(&self, rt_cfg: &RtConfig) -> () {
+        let scalar_modes = RtConfig::input_modes(&rt_cfg.scalar_inputs, rt_cfg.read_scalar_inputs(), self.hw_config.num_scalar_inputs);
+        if scalar_modes.iter().any(|mode| *mode != InputMode::Unused) {
+            assert!(!self.rt_data.scalar_input.is_empty(), "Scalar input is read, but no scalar ports are connected.");
+        }
+        if !rt_cfg.scalar_outputs.is_empty() {
+            assert!(!self.rt_data.scalar_output.is_empty(), "Scalar output is configured, but no scalar ports are connected.");
+        }
+        if let Some(control) = &rt_cfg.control {
+            if !control.enable.is_empty() || !control.done.is_empty() {
+                assert!(!self.rt_data.control_ports.is_empty(), "Control tokens are configured, but no control ports are connected.");
+            }
+        }
+    }
+
+    fn verify_control_ports(rt_cfg: &RtConfig, control_ports: &Vec<ControlPort>) -> () {
+        if let Some(control) = &rt_cfg.control {
+            for port in control.enable.iter() {
+                assert!(control_ports[*port].is_input(), "Enable token has to arrive on a control input.");
+            }
+            for port in control.done.iter() {
+                assert!(!control_ports[*port].is_input(), "Done token has to leave on a control output.");
+            }
+        }
+    }
+
+    // A stage may leave ALUs unused, but must not configure more ALUs than it has.
+    fn verify_alu_ops(hw_alus: &Vec<Vec<ALUHwConfig>>, rt_alus: &Vec<Vec<ALURtConfig>>) -> () {
         assert_eq!(hw_alus.len(), rt_alus.len());
-        for (hw_el, sw_el) in hw_alus.iter().zip(rt_alus.iter()) {
-            assert!(hw_el.supported_ops.contains(&sw_el.op));
+        for (hw_stage, rt_stage) in hw_alus.iter().zip(rt_alus.iter()) {
+            assert!(rt_stage.len() <= hw_stage.len(), "Stage configures more ALUs than are available.");
+            for (hw_el, sw_el) in hw_stage.iter().zip(rt_stage.iter()) {
+                assert!(hw_el.supported_ops.contains(&sw_el.op));
+            }
+            let targets: HashSet<usize> = rt_stage.iter().map(|cfg| cfg.target).collect();
+            assert_eq!(targets.len(), rt_stage.len(), "Two ALUs of a stage write the same pipeline register.");
+        }
+    }
+
+    // Every register an ALU reads or writes has to exist. The first stage reads from the input ports.
+    // State read through NEXT has to be written by an ALU of the same stage: a register without an ALU 
+    // passes the previous stage's value through, which would overwrite the state with every element.
+    fn verify_registers(hw_cfg: &HwConfig, rt_alus: &Vec<Vec<ALURtConfig>>) -> () {
+        for (stage, rt_stage) in rt_alus.iter().enumerate() {
+            let num_prev = if stage == 0 { hw_cfg.num_vector_input_ports } else { hw_cfg.num_registers_per_stage };
+            let targets: HashSet<usize> = rt_stage.iter().map(|cfg| cfg.target).collect();
+            for cfg in rt_stage.iter() {
+                assert!(cfg.target < hw_cfg.num_registers_per_stage, "ALU target register does not exist.");
+                for input in [cfg.in_a, cfg.in_b].iter() {
+                    match input {
+                        ALUInput::PREV(i) | ALUInput::PREV_BELOW(i) | ALUInput::PREV_STRIDE(i, _, _) => 
+                            assert!(*i < num_prev, "ALU input reads a register of the previous stage that does not exist."),
+                        ALUInput::NEXT(i) => {
+                            assert!(*i < hw_cfg.num_registers_per_stage, "ALU input reads a register that does not exist.");
+                            assert!(targets.contains(i), "ALU reads state through NEXT from a register no ALU of the stage writes.");
+                        },
+                        ALUInput::SCALAR(i) => 
+                            assert!(*i < hw_cfg.num_scalar_inputs, "ALU input reads a scalar input that does not exist."),
+                        ALUInput::CONSTANT(_) | ALUInput::COUNTER(_) => ()
+                    }
+                }
+            }
+        }
+    }
+
+    fn verify_shuffles(hw_cfg: &HwConfig, rt_cfg: &RtConfig) -> () {
+        if rt_cfg.shuffles.is_empty() {
+            return;
+        }
+        assert!(hw_cfg.shuffle_network.is_some(), "PCU does not have a shuffle network.");
+        for (stage, permutation) in rt_cfg.shuffles.iter() {
+            assert!(*stage < rt_cfg.alu_configs.len(), "Shuffled pipeline stage does not exist.");
+            permutation.verify(hw_cfg.num_simd_lanes);
+        }
+    }
+
+    fn verify_counters(hw_cfg: &HwConfig, rt_cfg: &RtConfig) -> () {
+        assert!(rt_cfg.counters.len() <= hw_cfg.num_counters, "Counter chain is longer than the available counters.");
+        for counter in rt_cfg.counters.iter() {
+            assert!(counter.par <= hw_cfg.num_simd_lanes, "Counter parallelization exceeds the SIMD lanes.");
+        }
+        for cfg in rt_cfg.alu_configs.iter().flatten() {
+            for input in [cfg.in_a, cfg.in_b].iter() {
+                if let ALUInput::COUNTER(level) = input {
+                    assert!(*level < rt_cfg.counters.len(), "ALU input reads a counter that does not exist.");
+                }
+            }
+        }
+    }
+
+    fn verify_control(hw_cfg: &HwConfig, rt_cfg: &RtConfig) -> () {
+        if let Some(control) = &rt_cfg.control {
+            assert!(control.iterations > 0 || !rt_cfg.counters.is_empty(), "A control batch needs at least one iteration.");
+            assert!(control.enable.iter().chain(control.done.iter()).all(|port| *port < hw_cfg.num_control_ports),
+                "Configured control port does not exist.");
+        }
+    }
+
+    fn verify_scalar_ports(hw_cfg: &HwConfig, rt_cfg: &RtConfig) -> () {
+        assert!(rt_cfg.scalar_outputs.len() <= hw_cfg.num_scalar_outputs, "Configured scalar output does not exist.");
+        for cfg in rt_cfg.scalar_outputs.iter() {
+            assert!(cfg.stage < rt_cfg.alu_configs.len(), "Scalar output reads a stage that does not exist.");
+            assert!(cfg.register < hw_cfg.num_registers_per_stage, "Scalar output reads a register that does not exist.");
+        }
+    }
+
+    // A connected port that is never read would fill up and stall its producer, so every connected port 
+    // has to be read, and every port an ALU reads has to be connected.
+    fn verify_inputs(hw_cfg: &HwConfig, rt_cfg: &RtConfig, num_inputs: usize) -> () {
+        let vector_modes = RtConfig::input_modes(&rt_cfg.vector_inputs, rt_cfg.read_vector_inputs(), hw_cfg.num_vector_input_ports);
+        let scalar_modes = RtConfig::input_modes(&rt_cfg.scalar_inputs, rt_cfg.read_scalar_inputs(), hw_cfg.num_scalar_inputs);
+        assert_eq!(vector_modes.len(), hw_cfg.num_vector_input_ports, "Every vector input port needs an input mode.");
+        assert_eq!(scalar_modes.len(), hw_cfg.num_scalar_inputs, "Every scalar input port needs an input mode.");
+        assert!(num_inputs <= hw_cfg.num_vector_input_ports, "More inputs connected than the PCU has ports.");
+
+        for (port, mode) in vector_modes.iter().enumerate() {
+            assert!(*mode != InputMode::Sticky, "Vector input ports cannot be sticky.");
+            if port < num_inputs {
+                assert!(*mode != InputMode::Unused, "Connected vector input is never read.");
+            } else {
+                assert!(*mode == InputMode::Unused, "Vector input is read, but not connected.");
+            }
+        }
+        for port in rt_cfg.read_vector_inputs() {
+            assert!(vector_modes[port] != InputMode::Unused, "ALU reads a vector input that is unused.");
+        }
+        for port in rt_cfg.read_scalar_inputs() {
+            assert!(scalar_modes[port] != InputMode::Unused, "ALU reads a scalar input that is unused.");
+        }
+    }
+
+    // Without a mapping, port i emits register i of the last stage, so that register has to exist.
+    fn verify_vector_outputs(hw_cfg: &HwConfig, rt_cfg: &RtConfig, num_outputs: usize) -> () {
+        if rt_cfg.vector_outputs.is_empty() {
+            assert!(num_outputs <= hw_cfg.num_registers_per_stage, "More vector outputs connected than the last stage has registers.");
+            return;
         }
+        assert_eq!(rt_cfg.vector_outputs.len(), num_outputs, "Every vector output port needs a configuration.");
+        for cfg in rt_cfg.vector_outputs.iter() {
+            assert!(cfg.stage < rt_cfg.alu_configs.len(), "Vector output reads a stage that does not exist.");
+            assert!(cfg.register < hw_cfg.num_registers_per_stage, "Vector output reads a register that does not exist.");
+        }
+    }
+
+    // Source of every vector output port, as (stage, register).
+    fn vector_output_sources(&self) -> Vec<(usize, usize)> {
+        if self.rt_config.vector_outputs.is_empty() {
+            let last = self.rt_data.pipeline_stages.len() - 1;
+            (0..self.rt_data.output.len()).map(|port| (last, port)).collect()
+        } else {
+            self.rt_config.vector_outputs.iter().map(|cfg| (cfg.stage, cfg.register)).collect()
+        }
+    }
+
+    // Cycles from issuing an element into the first stage until its result leaves the last stage.
+    pub fn latency(&self) -> usize {
+        self.rt_data.pipeline_stages.iter().map(|stage| stage.latency()).sum()
+    }
+
+    // Cycles between two successive elements entering the pipeline. Stages work on different elements 
+    // at the same time, so the throughput is bounded by the slowest stage rather than by the latency.
+    pub fn initiation_interval(&self) -> usize {
+        self.rt_data.pipeline_stages.iter().map(|stage| stage.initiation_interval()).max().unwrap_or(1).max(1)
     }
 
-    fn iterate(&mut self, input: &Vec<Vec<Scalar>>, time: Time) -> Time {
-        // Run a pipeline iteration.
-        let (data_out, t_fin) = self.rt_data.pipeline_stages.iter_mut().fold((input, time),
+    fn iterate(&mut self, input: &PipelineRegisters, time: Time) -> Time {
+        // Run a pipeline iteration. Each stage finishes the element `latency` cycles after it started,
+        // while the element issued next is already being processed by the earlier stages.
+        let mut finish_times = Vec::with_capacity(self.rt_data.pipeline_stages.len());
+        let _ = self.rt_data.pipeline_stages.iter_mut().fold((input, time),
         |(data, time), stage| {
-            stage.iterate(data, time)
+            let (data_out, t_stage) = stage.iterate(data, time);
+            finish_times.push(t_stage);
+            (data_out, t_stage)
         });
+        let t_fin = finish_times.last().copied().unwrap_or(time);
 
-        // Enqueue the outputs.
-        let data_out = data_out.clone();
-        self.rt_data.output
-            .iter()
-            .zip(data_out.iter())
-            .filter(|(_, data)| {data.len() > 0})
-            .for_each(|(sender, data)| {
-                sender.enqueue(&self.time, ChannelElement::new(t_fin, PCUData { data: data.clone() })).unwrap();
-        });
+        // Enqueue the outputs whose registers hold a valid result, at the time their stage finished.
+        for (sender, (stage, register)) in self.rt_data.output.iter().zip(self.vector_output_sources()) {
+            let registers = &self.rt_data.pipeline_stages[stage].registers;
+            if !registers.valid[register] || self.emitted_on_flush(stage, register) {
+                continue;
+            }
+            let element = PCUData::masked(registers.data[register].clone(), registers.mask.clone());
+            sender.enqueue(&self.time, ChannelElement::new(finish_times[stage], element)).unwrap();
+        }
+
+        for (sender, cfg) in self.rt_data.scalar_output.iter().zip(self.rt_config.scalar_outputs.iter()) {
+            let registers = &self.rt_data.pipeline_stages[cfg.stage].registers;
+            if !registers.valid[cfg.register] || self.emitted_on_flush(cfg.stage, cfg.register) {
+                continue;
+            }
+            if let Some(value) = PCU::scalar_output_value(cfg, &registers.data[cfg.register], &registers.mask) {
+                let t_out = finish_times[cfg.stage] + self.scalar_output_latency(cfg) as u64;
+                sender.enqueue(&self.time, ChannelElement::new(t_out, value)).unwrap();
+            }
+        }
         t_fin
     }
 
-    /*
+    // The first active lane, or the reduction of all active lanes. None if every lane is masked off.
+    fn scalar_output_value(cfg: &ScalarOutputConfig, lanes: &Vec<Scalar>, mask: &Vec<bool>) -> Option<Scalar> {
+        let mut lanes = lanes.iter()
+            .zip(mask.iter())
+            .filter(|(_, active)| **active)
+            .map(|(lane, _)| *lane);
+        let first = lanes.next()?;
+        match cfg.reduction {
+            Some(op) => Some(lanes.fold(first, |acc, x| op.apply(&acc, &x))),
+            None => Some(first)
+        }
+    }
+
+    fn scalar_output_latency(&self, cfg: &ScalarOutputConfig) -> usize {
+        cfg.reduction.map(|op| self.scalar_reduction_latency(op)).unwrap_or(0)
+    }
+
+    // Whether a register of a stage holds state, i.e. an ALU of the stage reads it through NEXT.
+    fn is_state_register(&self, stage: usize, register: usize) -> bool {
+        self.rt_config.alu_configs[stage].iter().any(|cfg| 
+            [cfg.in_a, cfg.in_b].iter().any(|input| matches!(input, ALUInput::NEXT(r) if *r == register)))
+    }
+
+    // A state register of a stage that never computed anything, e.g. because the input closed right away, 
+    // holds no state worth emitting.
+    fn holds_state(&self, stage: usize, register: usize) -> bool {
+        self.is_state_register(stage, register) && self.rt_data.pipeline_stages[stage].written[register]
+    }
+
+    fn emitted_on_flush(&self, stage: usize, register: usize) -> bool {
+        self.rt_config.flush == FlushPolicy::EmitState && self.is_state_register(stage, register)
+    }
+
+    // Waits until every element in flight finished at `t_last`, then emits the state registers if requested, 
+    // with the mask of the last element.
+    // The outputs close once `run` returns and the PCU drops its senders.
+    fn flush(&mut self, t_last: Time) {
+        if t_last > self.time.tick() {
+            self.time.advance(t_last);
+        }
+        if self.rt_config.flush != FlushPolicy::EmitState {
+            return;
+        }
+        for (sender, (stage, register)) in self.rt_data.output.iter().zip(self.vector_output_sources()) {
+            if self.holds_state(stage, register) {
+                let registers = &self.rt_data.pipeline_stages[stage].registers;
+                let element = PCUData::masked(registers.data[register].clone(), registers.mask.clone());
+                sender.enqueue(&self.time, ChannelElement::new(self.time.tick(), element)).unwrap();
+            }
+        }
+        for (sender, cfg) in self.rt_data.scalar_output.iter().zip(self.rt_config.scalar_outputs.iter()) {
+            if !self.holds_state(cfg.stage, cfg.register) {
+                continue;
+            }
+            let registers = &self.rt_data.pipeline_stages[cfg.stage].registers;
+            if let Some(value) = PCU::scalar_output_value(cfg, &registers.data[cfg.register], &registers.mask) {
+                let t_out = self.time.tick() + self.scalar_output_latency(cfg) as u64;
+                sender.enqueue(&self.time, ChannelElement::new(t_out, value)).unwrap();
+            }
+        }
+    }
+
+    // The scalar outputs reduce the lanes with a tree of dedicated ALUs, one level per halving of the lanes.
+    fn scalar_reduction_latency(&self, op: ALUOp) -> usize {
+        let levels = self.hw_config.num_simd_lanes.next_power_of_two().trailing_zeros() as usize;
+        levels * self.hw_config.scalar_reduction_timing.latency(op)
+    }
+
+    // Returns the current value of a sticky scalar input, or None if the input closed before its first value.
+    fn read_sticky(&mut self, port: usize) -> Option<Scalar> {
+        let receiver = &self.rt_data.scalar_input[port];
+        if self.rt_data.sticky_scalars[port].is_none() {
+            self.rt_data.sticky_scalars[port] = Some(receiver.dequeue(&self.time).ok()?.data);
+        }
+        loop {
+            match receiver.peek() {
+                PeekResult::Something(ChannelElement { time, data: _ }) if time <= self.time.tick() => {
+                    self.rt_data.sticky_scalars[port] = Some(receiver.dequeue(&self.time).unwrap().data);
+                },
+                _ => break
+            }
+        }
+        self.rt_data.sticky_scalars[port]
+    }
+
+    // The consumed inputs of the next iteration arrived by the returned cycle, or one of them closed. Waiting 
+    // for them before polling the config port means that a load sent ahead of them is never missed.
+    fn next_inputs_arrival(&self) -> Time {
+        let vector = self.rt_data.input.iter().zip(self.rt_data.vector_input_modes.iter())
+            .filter(|(_, mode)| **mode == InputMode::Consumed)
+            .map(|(receiver, _)| receiver.peek_next(&self.time).ok().map(|element| element.time));
+        let scalar = self.rt_data.scalar_input.iter().zip(self.rt_data.scalar_input_modes.iter())
+            .filter(|(_, mode)| **mode == InputMode::Consumed)
+            .map(|(receiver, _)| receiver.peek_next(&self.time).ok().map(|element| element.time));
+        vector.chain(scalar).flatten().fold(self.time.tick(), std::cmp::max)
+    }
+
+    // A config load takes effect between two iterations: the elements in flight finish (and flush their state) 
+    // under the old configuration by `t_last`, then the PCU is busy for the reconfiguration latency.
+    fn load_config(&mut self, t_last: Time) -> bool {
+        let Some(port) = &self.rt_data.config_port else {
+            return false;
+        };
+        let Some(load) = poll_config_load(port, &self.time, &self.next_inputs_arrival()) else {
+            return false;
+        };
+        self.verify_connections(&load.data.config);
+        self.flush(t_last.clone());
+        let ready = reconfigured_at(&load, t_last);
+        if ready > self.time.tick() {
+            self.time.advance(ready);
+        }
+        self.configure(load.data.config);
+        true
+    }
+
     fn iter_bubble(&mut self, time: Time) -> Time {
-        let bubble = vec![Scalar::I32(0); self.hw_config.alu_configs[0].len()];
-        self.iter(&bubble, time)
+        let bubble = PipelineRegisters::new(self.hw_config.num_vector_input_ports, self.hw_config.num_simd_lanes);
+        self.iterate(&bubble, time)
+    }
+
+    fn is_drained(&self) -> bool {
+        self.rt_data.pipeline_stages.iter().all(|stage| stage.registers.is_bubble())
     }
-     */
 
+    // Issues a bubble for every cycle since `last_iter_time` in which no input arrived.
+    // Bubbles do not change any register contents, so once the pipeline only holds bubbles, 
+    // further bubbles are not observable and we stop early.
     fn fill_with_bubbles_until_now(&mut self, mut last_iter_time: Time) -> () {
         let input_time = self.time.tick();
-        while last_iter_time < input_time {
-            // self.iter_bubble(last_iter_time.clone());
+        while last_iter_time < input_time && !self.is_drained() {
+            self.iter_bubble(last_iter_time.clone());
             last_iter_time += 1;
         }
     }
@@ -108,32 +637,104 @@ impl PCU {
 
 impl Context for PCU {
     fn init(&mut self) {
+        self.verify_connections(&self.rt_config);
     }
 
     fn run(&mut self) {
-        loop {
-            // Dequeue from every ALU selected input:
-            let selected_inputs = self.rt_config.alu_configs[0].get_input_regs();
-
-            // Fill an input vector with all zeros except for the selected inputs.
-            let mut input = 
-                vec![
-                    vec![Scalar::I32(0); self.hw_config.num_simd_lanes]; 
-                self.hw_config.num_vector_input_ports];
-
-            for i in selected_inputs {
-                let next = self.rt_data.input[i].dequeue(&self.time);
-                match next {
+        let mut next_issue = self.time.tick();
+        let mut batch_left = 0;
+        let mut t_last = self.time.tick();
+        'issue: loop {
+            if self.load_config(t_last.clone()) {
+                batch_left = 0;
+                next_issue = self.time.tick();
+                t_last = self.time.tick();
+            }
+
+            // In the token-based mode, a new batch only starts once every enable token is present.
+            if let Some(control) = &self.rt_config.control {
+                if batch_left == 0 {
+                    for port in control.enable.iter() {
+                        if !self.rt_data.control_ports[*port].wait(&self.time) {
+                            break 'issue;
+                        }
+                    }
+                    batch_left = control.iterations;
+                    if let Some(chain) = &mut self.rt_data.counter_chain {
+                        chain.reset();
+                    }
+                }
+            }
+
+            // The counter chain generates the loop indices of this iteration. Once it is exhausted, the PCU is 
+            // done, unless the control network starts another pass.
+            let counters = match &mut self.rt_data.counter_chain {
+                Some(chain) => match chain.next(self.hw_config.num_simd_lanes) {
+                    Some(values) => Some(values),
+                    None => break 'issue
+                },
+                None => None
+            };
+
+            // Fill an input vector with invalid registers except for the consumed inputs.
+            let mut input = PipelineRegisters::new(self.hw_config.num_vector_input_ports, self.hw_config.num_simd_lanes);
+
+            for (i, mode) in self.rt_data.vector_input_modes.iter().enumerate() {
+                if *mode != InputMode::Consumed {
+                    continue;
+                }
+                match self.rt_data.input[i].dequeue(&self.time) {
                     Ok(data) => {
-                        input[i] = data.data.data;
+                        let element = data.data;
+                        input.mask.iter_mut().zip(element.mask.iter()).for_each(|(mask, active)| *mask &= *active);
+                        input.data[i] = element.data;
+                        input.valid[i] = true;
                     }
-                    Err(_) => {
-                        return; // We currently close the pipeline when the instream is closed.
+                    Err(_) => break 'issue
+                }
+            }
+
+            // Read every scalar input. The value travels with the element.
+            input.scalars = vec![Scalar::Empty; self.hw_config.num_scalar_inputs];
+            for i in 0..self.hw_config.num_scalar_inputs {
+                let value = match self.rt_data.scalar_input_modes[i] {
+                    InputMode::Unused => continue,
+                    InputMode::Consumed => self.rt_data.scalar_input[i].dequeue(&self.time).ok().map(|data| data.data),
+                    InputMode::Sticky => self.read_sticky(i)
+                };
+                match value {
+                    Some(value) => input.scalars[i] = value,
+                    None => break 'issue
+                }
+            }
+
+            let chain_finished = counters.as_ref().map(|values| values.is_last()).unwrap_or(false);
+            if let Some(values) = counters {
+                input.mask.iter_mut().zip(values.mask.iter()).for_each(|(mask, active)| *mask &= *active);
+                input.counters = values.values;
+            }
+
+            self.fill_with_bubbles_until_now(next_issue);
+            let t_fin = self.iterate(&input, self.time.tick());
+            t_last = std::cmp::max(t_last, t_fin);
+
+            if let Some(control) = &self.rt_config.control {
+                batch_left = match self.rt_data.counter_chain {
+                    Some(_) => if chain_finished { 0 } else { 1 },
+                    None => batch_left - 1
+                };
+                if batch_left == 0 {
+                    control.done.iter().for_each(|port| self.rt_data.control_ports[*port].signal(&self.time, t_fin));
+                    if chain_finished && control.enable.is_empty() {
+                        break 'issue;
                     }
                 }
             }
-            self.iterate(&input, self.time.tick());
+
+            self.time.incr_cycles(self.initiation_interval() as u64);
+            next_issue = self.time.tick();
         }
+        self.flush(t_last);
     }
 }
 
@@ -141,11 +742,11 @@ impl Context for PCU {
 mod tests {
     use std::collections::HashSet;
 
-    use dam::{simulation::{InitializationOptionsBuilder, ProgramBuilder, RunOptions}, utility_contexts::{CheckerContext, GeneratorContext}};
+    use dam::{context::Context, simulation::{InitializationOptionsBuilder, ProgramBuilder, RunOptions}, structures::Time, utility_contexts::{CheckerContext, GeneratorContext}};
 
-    use crate::{alu::{ALUHwConfig, ALUInput, ALUOp, ALURtConfig}, pcu::PCUData, scalar::Scalar};
+    use crate::{alu::{ALUHwConfig, ALUInput, ALUOp, ALUOpTiming, ALURtConfig, ALUTimingTable}, control::{ConfigLoad, ControlPort, ControlToken}, counter::CounterConfig, pcu::PCUData, pipeline_stage::Permutation, scalar::Scalar, test_utils::{Controller, TimedChecker, TimedGenerator}};
 
-    use super::{HwConfig, RtConfig, PCU};
+    use super::{reduction_tree, ControlRtConfig, FlushPolicy, HwConfig, InputMode, RtConfig, ScalarOutputConfig, ShuffleHwConfig, VectorOutputConfig, PCU};
 
     #[test]
     fn simple_pcu_test() {
@@ -153,20 +754,24 @@ mod tests {
         const CHAN_SIZE: usize = 8;
         
         let hw_config = HwConfig {
-            alu_configs: vec![ALUHwConfig {
-                supported_ops: HashSet::from([ALUOp::ADD_I32, ALUOp::MUL_I32])
-            };1],
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32, ALUOp::MUL_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
             num_simd_lanes: 1,
+            num_registers_per_stage: 1,
             num_vector_input_ports: 2,
+            ..Default::default()
         };
 
         let rt_config = RtConfig {
-            alu_configs: vec![
+            alu_configs: vec![vec![
                 ALURtConfig{op:ALUOp::ADD_I32,
                             in_a:ALUInput::PREV(0),
                             in_b:ALUInput::PREV(1),
                             target: 0}
-                ;1]
+                ];1],
+            ..Default::default()
         };
 
         let (snd0, i0) = parent.bounded(CHAN_SIZE);
@@ -176,17 +781,17 @@ mod tests {
         let pcu = PCU::new(hw_config, rt_config, vec![i0, i1], vec![o0]);
 
         let snd0_gen = (0..10).map(|x| {
-            PCUData{data: vec![Scalar::I32(x)]}
+            PCUData::new(vec![Scalar::I32(x)])
         });
 
         let snd1_gen = (0..10).map(|x| {
-            PCUData{data: vec![Scalar::I32(2*x)]}
+            PCUData::new(vec![Scalar::I32(2*x)])
         });
 
         let rcv_gen = (0..10).map(|x| {
-            PCUData{data: vec![
+            PCUData::new(vec![
                 Scalar::I32(3*x)
-            ]}
+            ])
         });
 
         let gen0 = GeneratorContext::new(|| {snd0_gen}, snd0);
@@ -203,7 +808,1154 @@ mod tests {
             .run(RunOptions::default());
 
         const NUM_ELEMENTS: u64 = 10;
-        assert_eq!(executed.elapsed_cycles().unwrap(), NUM_ELEMENTS + ALUOp::ADD_I32.delay() as u64);
+        assert_eq!(executed.elapsed_cycles().unwrap(), NUM_ELEMENTS + ALUOp::ADD_I32.default_timing().latency as u64);
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn reconfiguration_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+        const LATENCY: usize = 5;
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32, ALUOp::MUL_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            ..Default::default()
+        };
+        let config = |op: ALUOp, constant: i32| RtConfig {
+            alu_configs: vec![vec![ALURtConfig { op: op, in_a: ALUInput::PREV(0), in_b: ALUInput::CONSTANT(Scalar::I32(constant)), target: 0 }]],
+            ..Default::default()
+        };
+
+        let (snd, input) = parent.bounded(CHAN_SIZE);
+        let (output, rcv) = parent.bounded(CHAN_SIZE);
+        let (config_snd, config_rcv) = parent.bounded(1);
+        let pcu = PCU::new(hw_config, config(ALUOp::ADD_I32, 1), vec![input], vec![output]).with_config_port(config_rcv);
+
+        // The load arrives with the first element, so every element is doubled instead of incremented.
+        let load = ConfigLoad { config: config(ALUOp::MUL_I32, 2), latency: LATENCY };
+        let elements = (0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(x)])).collect();
+        parent.add_child(Controller::new(vec![(Some(load), elements)], config_snd, snd));
+        parent.add_child(CheckerContext::new(|| (0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(2 * x)])), rcv));
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert_eq!(executed.elapsed_cycles().unwrap(), (LATENCY + NUM_ELEMENTS as usize + ALUOp::MUL_I32.default_timing().latency) as u64);
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn reconfiguration_after_done_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+        const LATENCY: usize = 5;
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32, ALUOp::MUL_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            num_control_ports: 1,
+            ..Default::default()
+        };
+        // Every configuration signals done once it processed its batch.
+        let config = |op: ALUOp, constant: i32| RtConfig {
+            alu_configs: vec![vec![ALURtConfig { op: op, in_a: ALUInput::PREV(0), in_b: ALUInput::CONSTANT(Scalar::I32(constant)), target: 0 }]],
+            control: Some(ControlRtConfig { enable: vec![], done: vec![0], iterations: NUM_ELEMENTS as usize }),
+            ..Default::default()
+        };
+
+        let (snd, input) = parent.bounded(CHAN_SIZE);
+        let (output, rcv) = parent.bounded(CHAN_SIZE);
+        let (config_snd, config_rcv) = parent.bounded(1);
+        let (done, done_rcv) = parent.bounded(CHAN_SIZE);
+        let pcu = PCU::new(hw_config, config(ALUOp::ADD_I32, 1), vec![input], vec![output])
+            .with_config_port(config_rcv)
+            .with_control_ports(vec![ControlPort::Output(done)]);
+
+        // The controller only sends the second load once the first batch is done. Until then, the PCU has to 
+        // keep going without a load.
+        let load = ConfigLoad { config: config(ALUOp::MUL_I32, 2), latency: LATENCY };
+        let elements = || (0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(x)])).collect::<Vec<_>>();
+        let controller = Controller::new(vec![(None, elements()), (Some(load), elements())], config_snd, snd).with_done(done_rcv);
+        let expected = (0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(x + 1)]))
+            .chain((0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(2 * x)])));
+        let rcv = TimedChecker::new(expected.collect(), rcv);
+        let arrivals = rcv.arrivals();
+
+        parent.add_child(controller);
+        parent.add_child(rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+
+        // The second batch goes out once the done token reached the controller and the PCU reconfigured.
+        let num = NUM_ELEMENTS as u64;
+        let add = ALUOp::ADD_I32.default_timing().latency as u64;
+        let mul = ALUOp::MUL_I32.default_timing().latency as u64;
+        let second_load = std::cmp::max(num, num - 1 + add);
+        let expected_arrivals: Vec<Time> = (0..num).map(|x| Time::new(x + add))
+            .chain((0..num).map(|x| Time::new(second_load + LATENCY as u64 + x + mul)))
+            .collect();
+        assert_eq!(*arrivals.lock().unwrap(), expected_arrivals);
+    }
+
+    #[test]
+    fn reconfiguration_while_busy_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+        const LATENCY: usize = 5;
+        const ADD_LATENCY: usize = 4;
+
+        let mut timing = ALUTimingTable::default();
+        timing.set(ALUOp::ADD_I32, ALUOpTiming { latency: ADD_LATENCY, initiation_interval: 1 });
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32, ALUOp::MUL_I32]),
+                timing: timing
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            ..Default::default()
+        };
+        let config = |op: ALUOp, constant: i32| RtConfig {
+            alu_configs: vec![vec![ALURtConfig { op: op, in_a: ALUInput::PREV(0), in_b: ALUInput::CONSTANT(Scalar::I32(constant)), target: 0 }]],
+            ..Default::default()
+        };
+
+        let (snd, input) = parent.bounded(CHAN_SIZE);
+        let (output, rcv) = parent.bounded(CHAN_SIZE);
+        let (config_snd, config_rcv) = parent.bounded(1);
+        let pcu = PCU::new(hw_config, config(ALUOp::ADD_I32, 1), vec![input], vec![output]).with_config_port(config_rcv);
+
+        // The load arrives right after the last element of the first batch was issued, while that element is 
+        // still in the pipeline. The reconfiguration starts once the pipeline drained.
+        let load = ConfigLoad { config: config(ALUOp::MUL_I32, 2), latency: LATENCY };
+        let elements = || (0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(x)])).collect::<Vec<_>>();
+        let controller = Controller::new(vec![(None, elements()), (Some(load), elements())], config_snd, snd);
+        let expected = (0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(x + 1)]))
+            .chain((0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(2 * x)])));
+        let rcv = TimedChecker::new(expected.collect(), rcv);
+        let arrivals = rcv.arrivals();
+
+        parent.add_child(controller);
+        parent.add_child(rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
         assert!(executed.passed());
+
+        let num = NUM_ELEMENTS as u64;
+        let mul = ALUOp::MUL_I32.default_timing().latency as u64;
+        let drained = num - 1 + ADD_LATENCY as u64;
+        let expected_arrivals: Vec<Time> = (0..num).map(|x| Time::new(x + ADD_LATENCY as u64))
+            .chain((0..num).map(|x| Time::new(drained + LATENCY as u64 + x + mul)))
+            .collect();
+        assert_eq!(*arrivals.lock().unwrap(), expected_arrivals);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn pipelined_pcu_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+
+        let mut timing = ALUTimingTable::default();
+        timing.set(ALUOp::MUL_I32, ALUOpTiming { latency: 2, initiation_interval: 2 });
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32, ALUOp::MUL_I32]),
+                timing: timing
+            }];3],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 2,
+            ..Default::default()
+        };
+
+        // Out := (a + b) * 2 + 1
+        let rt_config = RtConfig {
+            alu_configs: vec![
+                vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::PREV(1), target: 0}],
+                vec![ALURtConfig{op:ALUOp::MUL_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::CONSTANT(Scalar::I32(2)), target: 0}],
+                vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::CONSTANT(Scalar::I32(1)), target: 0}],
+            ],
+            ..Default::default()
+        };
+
+        let (snd0, i0) = parent.bounded(CHAN_SIZE);
+        let (snd1, i1) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+
+        let pcu = PCU::new(hw_config, rt_config, vec![i0, i1], vec![o0]);
+        assert_eq!(pcu.latency(), 4);
+        assert_eq!(pcu.initiation_interval(), 2);
+        let latency = pcu.latency() as u64;
+        let ii = pcu.initiation_interval() as u64;
+
+        let gen0 = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd0);
+        let gen1 = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(2*x)])), snd1);
+        let rcv = CheckerContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(6*x + 1)])), rcv);
+
+        parent.add_child(gen0);
+        parent.add_child(gen1);
+        parent.add_child(rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        // One element is issued every `ii` cycles, the last one leaves the pipeline `latency` cycles later.
+        assert_eq!(executed.elapsed_cycles().unwrap(), (NUM_ELEMENTS as u64 - 1) * ii + latency + 1);
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn accumulator_stalls_on_recurrence_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+        const ADD_LATENCY: usize = 3;
+
+        let mut timing = ALUTimingTable::default();
+        timing.set(ALUOp::ADD_I32, ALUOpTiming { latency: ADD_LATENCY, initiation_interval: 1 });
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: timing
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            ..Default::default()
+        };
+
+        // Out := running sum of the inputs
+        let rt_config = RtConfig {
+            alu_configs: vec![
+                vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::NEXT(0), target: 0}]
+            ],
+            ..Default::default()
+        };
+
+        let (snd, i0) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+
+        let pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0]);
+        // Every element has to wait for the previous sum to leave the adder.
+        assert_eq!(pcu.initiation_interval(), ADD_LATENCY);
+
+        let gen = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd);
+        let rcv = CheckerContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x*(x+1)/2)])), rcv);
+
+        parent.add_child(gen);
+        parent.add_child(rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert_eq!(
+            executed.elapsed_cycles().unwrap(),
+            (NUM_ELEMENTS as u64 - 1) * ADD_LATENCY as u64 + ADD_LATENCY as u64 + 1);
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn sparse_accumulator_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const ADD_LATENCY: u64 = 2;
+
+        let mut timing = ALUTimingTable::default();
+        timing.set(ALUOp::ADD_I32, ALUOpTiming { latency: ADD_LATENCY as usize, initiation_interval: 1 });
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: timing
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            ..Default::default()
+        };
+
+        // Out := running sum of the inputs
+        let rt_config = RtConfig {
+            alu_configs: vec![
+                vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::NEXT(0), target: 0}]
+            ],
+            ..Default::default()
+        };
+
+        let (snd, i0) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+        let pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0]);
+
+        // The gaps between the inputs are filled with bubbles, which keep the running sum. The input at 8 comes 
+        // while the recurrence still holds the ALU, so it starts at 9.
+        let element = |x: i32| PCUData::new(vec![Scalar::I32(x)]);
+        let gen = TimedGenerator::new(vec![(0, element(1)), (2, element(2)), (7, element(3)), (8, element(4)), (20, element(5))], snd);
+        let rcv = TimedChecker::new(vec![element(1), element(3), element(6), element(10), element(15)], rcv);
+        let arrivals = rcv.arrivals();
+
+        parent.add_child(gen);
+        parent.add_child(rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+
+        let issued = [0, 2, 7, 9, 20];
+        assert_eq!(*arrivals.lock().unwrap(), issued.iter().map(|t| Time::new(t + ADD_LATENCY)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn dot_product_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+        const SIMD: usize = 4;
+
+        assert_eq!(reduction_tree(ALUOp::ADD_I32, 0, 1, Scalar::I32(0)).len(), 1);
+        assert_eq!(reduction_tree(ALUOp::ADD_I32, 0, SIMD, Scalar::I32(0)).len(), 2);
+        assert_eq!(reduction_tree(ALUOp::ADD_I32, 0, SIMD + 1, Scalar::I32(0)).len(), 3);
+
+        let rt_config = RtConfig::dot_product(ALUOp::MUL_I32, ALUOp::ADD_I32, SIMD, Scalar::I32(0));
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32, ALUOp::MUL_I32]),
+                timing: ALUTimingTable::default()
+            }]; rt_config.alu_configs.len()],
+            num_simd_lanes: SIMD,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 2,
+            ..Default::default()
+        };
+
+        let (snd0, i0) = parent.bounded(CHAN_SIZE);
+        let (snd1, i1) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+
+        let pcu = PCU::new(hw_config, rt_config, vec![i0, i1], vec![o0]);
+
+        let lhs = |x: i32| (0..SIMD as i32).map(|lane| x + lane).collect::<Vec<_>>();
+        let rhs = |_: i32| (0..SIMD as i32).map(|lane| lane + 1).collect::<Vec<_>>();
+
+        // Lane 0 holds the dot product, lane i the sum of the products of lanes i..SIMD.
+        let expected = move |x: i32| {
+            let products: Vec<i32> = lhs(x).iter().zip(rhs(x).iter()).map(|(a, b)| a * b).collect();
+            PCUData::new((0..SIMD).map(|lane| Scalar::I32(products[lane..].iter().sum())).collect())
+        };
+
+        let gen0 = GeneratorContext::new(move || {0..NUM_ELEMENTS}.map(move |x| PCUData::new(lhs(x).into_iter().map(Scalar::I32).collect())), snd0);
+        let gen1 = GeneratorContext::new(move || {0..NUM_ELEMENTS}.map(move |x| PCUData::new(rhs(x).into_iter().map(Scalar::I32).collect())), snd1);
+        let rcv = CheckerContext::new(move || {0..NUM_ELEMENTS}.map(expected), rcv);
+
+        parent.add_child(gen0);
+        parent.add_child(gen1);
+        parent.add_child(rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn shuffle_stage_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+        const SIMD: usize = 4;
+        const SHUFFLE_LATENCY: usize = 2;
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];2],
+            num_simd_lanes: SIMD,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            shuffle_network: Some(ShuffleHwConfig { latency: SHUFFLE_LATENCY }),
+            ..Default::default()
+        };
+
+        // Out := rotate(a, 1) + 1
+        let rt_config = RtConfig {
+            alu_configs: vec![
+                vec![],
+                vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::CONSTANT(Scalar::I32(1)), target: 0}]
+            ],
+            shuffles: [(0, Permutation::Rotate(1))].into_iter().collect(),
+            ..Default::default()
+        };
+
+        let (snd, i0) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+
+        let pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0]);
+        assert_eq!(pcu.latency(), SHUFFLE_LATENCY + ALUOp::ADD_I32.default_timing().latency);
+
+        let gen = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(
+            (0..SIMD as i32).map(|lane| Scalar::I32(x + lane)).collect()
+        )), snd);
+        let rcv = CheckerContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(
+            (0..SIMD as i32).map(|lane| Scalar::I32(x + (lane + 1) % SIMD as i32 + 1)).collect()
+        )), rcv);
+
+        parent.add_child(gen);
+        parent.add_child(rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn scalar_ports_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+        const SIMD: usize = 4;
+        const REDUCTION_LATENCY: usize = 3;
+
+        let mut reduction_timing = ALUTimingTable::default();
+        reduction_timing.set(ALUOp::ADD_I32, ALUOpTiming { latency: REDUCTION_LATENCY, initiation_interval: 1 });
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: SIMD,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            num_scalar_inputs: 1,
+            num_scalar_outputs: 2,
+            scalar_reduction_timing: reduction_timing,
+            ..Default::default()
+        };
+
+        // Out := a + s, emitted as a vector, as lane 0 and as the sum of all lanes.
+        let rt_config = RtConfig {
+            alu_configs: vec![vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::SCALAR(0), target: 0}]],
+            scalar_outputs: vec![
+                ScalarOutputConfig { stage: 0, register: 0, reduction: None },
+                ScalarOutputConfig { stage: 0, register: 0, reduction: Some(ALUOp::ADD_I32) }
+            ],
+            ..Default::default()
+        };
+
+        let (snd, i0) = parent.bounded(CHAN_SIZE);
+        let (scalar_snd, scalar_i0) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+        let (scalar_o0, scalar_rcv0) = parent.bounded(CHAN_SIZE);
+        let (scalar_o1, scalar_rcv1) = parent.bounded(CHAN_SIZE);
+
+        let pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0])
+            .with_scalar_ports(vec![scalar_i0], vec![scalar_o0, scalar_o1]);
+
+        let lanes = |x: i32| (0..SIMD as i32).map(move |lane| x + lane);
+
+        let gen = GeneratorContext::new(move || {0..NUM_ELEMENTS}.map(move |x| PCUData::new(lanes(x).map(Scalar::I32).collect())), snd);
+        let scalar_gen = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| Scalar::I32(100 * x)), scalar_snd);
+        let rcv = CheckerContext::new(move || {0..NUM_ELEMENTS}.map(move |x| PCUData::new(
+            lanes(x).map(|v| Scalar::I32(v + 100 * x)).collect()
+        )), rcv);
+        let scalar_rcv0 = CheckerContext::new(|| {0..NUM_ELEMENTS}.map(|x| Scalar::I32(x + 100 * x)), scalar_rcv0);
+        let scalar_rcv1 = CheckerContext::new(move || {0..NUM_ELEMENTS}.map(move |x| 
+            Scalar::I32(lanes(x).map(|v| v + 100 * x).sum())), scalar_rcv1);
+
+        parent.add_child(gen);
+        parent.add_child(scalar_gen);
+        parent.add_child(rcv);
+        parent.add_child(scalar_rcv0);
+        parent.add_child(scalar_rcv1);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert!(executed.passed());
+        // The sum of the last element leaves the two levels of the reduction tree last.
+        assert_eq!(
+            executed.elapsed_cycles().unwrap(), 
+            (NUM_ELEMENTS as usize + ALUOp::ADD_I32.default_timing().latency + 2 * REDUCTION_LATENCY) as u64);
+    }
+
+    #[test]
+    #[should_panic(expected = "Scalar input is read, but no scalar ports are connected.")]
+    fn scalar_input_not_connected_test() {
+        let mut parent = ProgramBuilder::default();
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            num_scalar_inputs: 1,
+            ..Default::default()
+        };
+        let rt_config = RtConfig {
+            alu_configs: vec![vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::SCALAR(0), target: 0}]],
+            ..Default::default()
+        };
+
+        let (_snd, i0) = parent.bounded::<PCUData>(8);
+        let (o0, _rcv) = parent.bounded::<PCUData>(8);
+        let mut pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0]);
+        pcu.init();
+    }
+
+    #[test]
+    fn control_tokens_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_BATCHES: usize = 2;
+        const BATCH_SIZE: usize = 5;
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            num_control_ports: 2,
+            ..Default::default()
+        };
+
+        let rt_config = RtConfig {
+            alu_configs: vec![vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::CONSTANT(Scalar::I32(1)), target: 0}]],
+            control: Some(ControlRtConfig { enable: vec![0], done: vec![1], iterations: BATCH_SIZE }),
+            ..Default::default()
+        };
+
+        let (snd, i0) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+        let (enable_snd, enable) = parent.bounded(CHAN_SIZE);
+        let (done, done_rcv) = parent.bounded(CHAN_SIZE);
+
+        let pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0])
+            .with_control_ports(vec![ControlPort::Input(enable), ControlPort::Output(done)]);
+
+        // The data is there from the start, but nothing is issued before the first enable token.
+        const NUM_ELEMENTS: i32 = (NUM_BATCHES * BATCH_SIZE) as i32;
+        const FIRST_ENABLE: u64 = 20;
+        let gen = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd);
+        let enable_gen = TimedGenerator::new(vec![(FIRST_ENABLE, ControlToken); NUM_BATCHES], enable_snd);
+        let rcv = TimedChecker::new((0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(x + 1)])).collect(), rcv);
+        let arrivals = rcv.arrivals();
+        let done_rcv = CheckerContext::new(|| std::iter::repeat(ControlToken).take(NUM_BATCHES), done_rcv);
+
+        parent.add_child(gen);
+        parent.add_child(enable_gen);
+        parent.add_child(rcv);
+        parent.add_child(done_rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert!(executed.passed());
+        assert_eq!(arrivals.lock().unwrap()[0], Time::new(FIRST_ENABLE + ALUOp::ADD_I32.default_timing().latency as u64));
+    }
+
+    #[test]
+    #[should_panic(expected = "Control tokens are configured, but no control ports are connected.")]
+    fn control_ports_not_connected_test() {
+        let mut parent = ProgramBuilder::default();
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            num_control_ports: 2,
+            ..Default::default()
+        };
+        let rt_config = RtConfig {
+            alu_configs: vec![vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::CONSTANT(Scalar::I32(1)), target: 0}]],
+            control: Some(ControlRtConfig { enable: vec![0], done: vec![1], iterations: 1 }),
+            ..Default::default()
+        };
+
+        let (_snd, i0) = parent.bounded::<PCUData>(8);
+        let (o0, _rcv) = parent.bounded::<PCUData>(8);
+        let mut pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0]);
+        pcu.init();
+    }
+
+    #[test]
+    fn counter_chain_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const SIMD: usize = 4;
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: SIMD,
+            num_registers_per_stage: 1,
+            num_counters: 2,
+            ..Default::default()
+        };
+
+        // out[lane] = i + j, for i in 0..2 and a vectorized j in 0..8.
+        let rt_config = RtConfig {
+            alu_configs: vec![vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::COUNTER(0), in_b:ALUInput::COUNTER(1), target: 0}]],
+            counters: vec![
+                CounterConfig { min: 0, max: 2, stride: 1, par: 1 },
+                CounterConfig { min: 0, max: 8, stride: 1, par: SIMD },
+            ],
+            ..Default::default()
+        };
+
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+        let pcu = PCU::new(hw_config, rt_config, vec![], vec![o0]);
+
+        let expected = || (0..2).flat_map(|i| (0..2).map(move |j| PCUData::new(
+            (0..SIMD as i32).map(|lane| Scalar::I32(i + j * SIMD as i32 + lane)).collect()
+        )));
+        let rcv = CheckerContext::new(expected, rcv);
+
+        parent.add_child(rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn masked_reduction_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+        const SIMD: usize = 4;
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];2],
+            num_simd_lanes: SIMD,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            ..Default::default()
+        };
+        let rt_config = RtConfig::vector_sum(ALUOp::ADD_I32, SIMD, Scalar::I32(0));
+
+        let (snd, i0) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+        let pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0]);
+
+        // The last lane is masked off and holds garbage, which must not end up in the sum.
+        let mask = vec![true, true, true, false];
+        let input_mask = mask.clone();
+        let gen = GeneratorContext::new(move || {0..NUM_ELEMENTS}.map(move |x| PCUData::masked(
+            vec![Scalar::I32(x), Scalar::I32(x + 1), Scalar::I32(x + 2), Scalar::I32(100)], input_mask.clone()
+        )), snd);
+        // Lane 0 holds the sum of the active lanes. The masked-off lane is never written and keeps its reset value.
+        let rcv = CheckerContext::new(move || {0..NUM_ELEMENTS}.map(move |x| PCUData::masked(
+            vec![Scalar::I32(3 * x + 3), Scalar::I32(2 * x + 3), Scalar::I32(x + 2), Scalar::I32(0)], mask.clone()
+        )), rcv);
+
+        parent.add_child(gen);
+        parent.add_child(rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn single_lane_vector_sum_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            ..Default::default()
+        };
+        let rt_config = RtConfig::vector_sum(ALUOp::ADD_I32, 1, Scalar::I32(0));
+        assert_eq!(rt_config.alu_configs.len(), 1);
+
+        let (snd, i0) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+        let pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0]);
+
+        // The sum of a single lane is the lane itself.
+        let gen = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd);
+        let rcv = CheckerContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), rcv);
+
+        parent.add_child(gen);
+        parent.add_child(rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert!(executed.passed());
+    }
+
+    #[test]
+    #[should_panic(expected = "A PCU config needs at least one pipeline stage.")]
+    fn config_without_stages_test() {
+        let mut parent = ProgramBuilder::default();
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            ..Default::default()
+        };
+
+        let (_snd, i0) = parent.bounded::<PCUData>(8);
+        let (o0, _rcv) = parent.bounded::<PCUData>(8);
+        let _ = PCU::new(hw_config, RtConfig::default(), vec![i0], vec![o0]);
+    }
+
+    #[test]
+    fn output_mapping_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32, ALUOp::MUL_I32]),
+                timing: ALUTimingTable::default()
+            }];2],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            num_scalar_outputs: 1,
+            ..Default::default()
+        };
+
+        // Stage 0 computes a + 1, stage 1 doubles it. Both intermediate and final results leave the PCU.
+        let rt_config = RtConfig {
+            alu_configs: vec![
+                vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::CONSTANT(Scalar::I32(1)), target: 0}],
+                vec![ALURtConfig{op:ALUOp::MUL_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::CONSTANT(Scalar::I32(2)), target: 0}]
+            ],
+            vector_outputs: vec![
+                VectorOutputConfig { stage: 1, register: 0 },
+                VectorOutputConfig { stage: 0, register: 0 }
+            ],
+            scalar_outputs: vec![ScalarOutputConfig { stage: 0, register: 0, reduction: None }],
+            ..Default::default()
+        };
+
+        let (snd, i0) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv0) = parent.bounded(CHAN_SIZE);
+        let (o1, rcv1) = parent.bounded(CHAN_SIZE);
+        let (scalar_o0, scalar_rcv) = parent.bounded(CHAN_SIZE);
+
+        let pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0, o1])
+            .with_scalar_ports(vec![], vec![scalar_o0]);
+
+        let gen = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd);
+        let rcv0 = CheckerContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(2 * (x + 1))])), rcv0);
+        let rcv1 = CheckerContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x + 1)])), rcv1);
+        let scalar_rcv = CheckerContext::new(|| {0..NUM_ELEMENTS}.map(|x| Scalar::I32(x + 1)), scalar_rcv);
+
+        parent.add_child(gen);
+        parent.add_child(rcv0);
+        parent.add_child(rcv1);
+        parent.add_child(scalar_rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert!(executed.passed());
+    }
+
+    #[test]
+    #[should_panic(expected = "More vector outputs connected than the last stage has registers.")]
+    fn implicit_output_mapping_test() {
+        let mut parent = ProgramBuilder::default();
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            ..Default::default()
+        };
+        let rt_config = RtConfig {
+            alu_configs: vec![vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::CONSTANT(Scalar::I32(1)), target: 0}]],
+            ..Default::default()
+        };
+
+        let (_snd, i0) = parent.bounded::<PCUData>(8);
+        let (o0, _rcv0) = parent.bounded::<PCUData>(8);
+        let (o1, _rcv1) = parent.bounded::<PCUData>(8);
+        let _ = PCU::new(hw_config, rt_config, vec![i0], vec![o0, o1]);
+    }
+
+    #[test]
+    fn later_stage_input_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32, ALUOp::MUL_I32]),
+                timing: ALUTimingTable::default()
+            }];2],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 2,
+            num_vector_input_ports: 2,
+            ..Default::default()
+        };
+
+        // Input 1 is only read by the second stage; it reaches it through the pass-through register.
+        let rt_config = RtConfig {
+            alu_configs: vec![
+                vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::CONSTANT(Scalar::I32(1)), target: 0}],
+                vec![ALURtConfig{op:ALUOp::MUL_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::PREV(1), target: 0}]
+            ],
+            ..Default::default()
+        };
+        assert_eq!(rt_config.read_vector_inputs(), HashSet::from([0, 1]));
+
+        let (snd0, i0) = parent.bounded(CHAN_SIZE);
+        let (snd1, i1) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+        let pcu = PCU::new(hw_config, rt_config, vec![i0, i1], vec![o0]);
+
+        let gen0 = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd0);
+        let gen1 = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x + 2)])), snd1);
+        let rcv = CheckerContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32((x + 1) * (x + 2))])), rcv);
+
+        parent.add_child(gen0);
+        parent.add_child(gen1);
+        parent.add_child(rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn sticky_scalar_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            num_scalar_inputs: 1,
+            ..Default::default()
+        };
+
+        // The scalar is a loop invariant: it is sent once and used by every iteration.
+        let rt_config = RtConfig {
+            alu_configs: vec![vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::SCALAR(0), target: 0}]],
+            scalar_inputs: vec![InputMode::Sticky],
+            ..Default::default()
+        };
+
+        let (snd, i0) = parent.bounded(CHAN_SIZE);
+        let (scalar_snd, scalar_i0) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+        let pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0])
+            .with_scalar_ports(vec![scalar_i0], vec![]);
+
+        let gen = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd);
+        let scalar_gen = GeneratorContext::new(|| std::iter::once(Scalar::I32(100)), scalar_snd);
+        let rcv = CheckerContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x + 100)])), rcv);
+
+        parent.add_child(gen);
+        parent.add_child(scalar_gen);
+        parent.add_child(rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert!(executed.passed());
+    }
+
+    #[test]
+    #[should_panic(expected = "Connected vector input is never read.")]
+    fn unused_input_port_test() {
+        let mut parent = ProgramBuilder::default();
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 2,
+            ..Default::default()
+        };
+        let rt_config = RtConfig {
+            alu_configs: vec![vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::CONSTANT(Scalar::I32(1)), target: 0}]],
+            ..Default::default()
+        };
+
+        let (_snd0, i0) = parent.bounded::<PCUData>(8);
+        let (_snd1, i1) = parent.bounded::<PCUData>(8);
+        let (o0, _rcv) = parent.bounded::<PCUData>(8);
+        let _ = PCU::new(hw_config, rt_config, vec![i0, i1], vec![o0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ALU reads state through NEXT from a register no ALU of the stage writes.")]
+    fn next_of_pass_through_register_test() {
+        let mut parent = ProgramBuilder::default();
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 2,
+            num_vector_input_ports: 2,
+            ..Default::default()
+        };
+        // Register 1 is passed through from input 1, so it cannot hold an accumulator.
+        let rt_config = RtConfig {
+            alu_configs: vec![vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::NEXT(1), target: 0}]],
+            vector_inputs: vec![InputMode::Consumed, InputMode::Consumed],
+            ..Default::default()
+        };
+
+        let (_snd0, i0) = parent.bounded::<PCUData>(8);
+        let (_snd1, i1) = parent.bounded::<PCUData>(8);
+        let (o0, _rcv) = parent.bounded::<PCUData>(8);
+        let _ = PCU::new(hw_config, rt_config, vec![i0, i1], vec![o0]);
+    }
+
+    #[test]
+    fn flush_emits_state_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            num_scalar_outputs: 1,
+            ..Default::default()
+        };
+
+        // Out := sum(a), emitted once after the input closed.
+        let rt_config = RtConfig {
+            alu_configs: vec![vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::NEXT(0), target: 0}]],
+            scalar_outputs: vec![ScalarOutputConfig { stage: 0, register: 0, reduction: None }],
+            flush: FlushPolicy::EmitState,
+            ..Default::default()
+        };
+
+        let (snd, i0) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+        let (scalar_o0, scalar_rcv) = parent.bounded(CHAN_SIZE);
+        let pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0])
+            .with_scalar_ports(vec![], vec![scalar_o0]);
+
+        let total = (0..NUM_ELEMENTS).sum::<i32>();
+        let gen = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd);
+        let rcv = CheckerContext::new(move || std::iter::once(PCUData::new(vec![Scalar::I32(total)])), rcv);
+        let scalar_rcv = CheckerContext::new(move || std::iter::once(Scalar::I32(total)), scalar_rcv);
+
+        parent.add_child(gen);
+        parent.add_child(rcv);
+        parent.add_child(scalar_rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn flush_masked_state_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 2,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            num_scalar_outputs: 1,
+            ..Default::default()
+        };
+
+        // Out := sum(a) over the active lane only. The masked-off lane is never computed.
+        let rt_config = RtConfig {
+            alu_configs: vec![vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::NEXT(0), target: 0}]],
+            scalar_outputs: vec![ScalarOutputConfig { stage: 0, register: 0, reduction: Some(ALUOp::ADD_I32) }],
+            flush: FlushPolicy::EmitState,
+            ..Default::default()
+        };
+
+        let (snd, i0) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+        let (scalar_o0, scalar_rcv) = parent.bounded(CHAN_SIZE);
+        let pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0])
+            .with_scalar_ports(vec![], vec![scalar_o0]);
+
+        let total = (0..NUM_ELEMENTS).sum::<i32>();
+        let gen = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| 
+            PCUData::masked(vec![Scalar::I32(x), Scalar::I32(x)], vec![true, false])), snd);
+        let rcv = CheckerContext::new(move || std::iter::once(
+            PCUData::masked(vec![Scalar::I32(total), Scalar::I32(0)], vec![true, false])), rcv);
+        let scalar_rcv = CheckerContext::new(move || std::iter::once(Scalar::I32(total)), scalar_rcv);
+
+        parent.add_child(gen);
+        parent.add_child(rcv);
+        parent.add_child(scalar_rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn flush_without_elements_test() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+
+        let hw_config = HwConfig {
+            alu_configs: vec![vec![ALUHwConfig {
+                supported_ops: HashSet::from([ALUOp::ADD_I32]),
+                timing: ALUTimingTable::default()
+            }];1],
+            num_simd_lanes: 1,
+            num_registers_per_stage: 1,
+            num_vector_input_ports: 1,
+            num_scalar_outputs: 1,
+            ..Default::default()
+        };
+        let rt_config = RtConfig {
+            alu_configs: vec![vec![ALURtConfig{op:ALUOp::ADD_I32, in_a:ALUInput::PREV(0), in_b:ALUInput::NEXT(0), target: 0}]],
+            scalar_outputs: vec![ScalarOutputConfig { stage: 0, register: 0, reduction: None }],
+            flush: FlushPolicy::EmitState,
+            ..Default::default()
+        };
+
+        let (snd, i0) = parent.bounded(CHAN_SIZE);
+        let (o0, rcv) = parent.bounded(CHAN_SIZE);
+        let (scalar_o0, scalar_rcv) = parent.bounded(CHAN_SIZE);
+        let pcu = PCU::new(hw_config, rt_config, vec![i0], vec![o0])
+            .with_scalar_ports(vec![], vec![scalar_o0]);
+
+        // The input closes right away, so there is no state to emit.
+        let gen = GeneratorContext::new(|| std::iter::empty::<PCUData>(), snd);
+        let rcv = CheckerContext::new(|| std::iter::empty::<PCUData>(), rcv);
+        let scalar_rcv = CheckerContext::new(|| std::iter::empty::<Scalar>(), scalar_rcv);
+
+        parent.add_child(gen);
+        parent.add_child(rcv);
+        parent.add_child(scalar_rcv);
+        parent.add_child(pcu);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+
+        assert!(executed.passed());
+    }
+}
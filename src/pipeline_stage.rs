@@ -1,60 +1,254 @@
 // PipelineStage consisting out of ALU's and Registers
 use dam::structures::Time;
 
-use crate::{alu::{ALUInput, ALURtConfig}, scalar::Scalar};
+use crate::{alu::{ALUInput, ALUOpTiming, ALURtConfig}, scalar::Scalar};
+
+// Contents of the pipeline registers between two stages, indexed data[register][lane]. 
+// A register is only consumed by the next stage if its valid bit is set; a set of registers 
+// without any valid bit is a bubble.
+// The values of the scalar inputs and of the counters travel along with the element they were read for.
+// The mask holds one bit per lane; masked-off lanes (e.g. the tail of a loop) are carried along, but not computed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PipelineRegisters {
+    pub data: Vec<Vec<Scalar>>,
+    pub valid: Vec<bool>,
+    pub scalars: Vec<Scalar>,
+    pub counters: Vec<Vec<Scalar>>, // counters[level][lane]
+    pub mask: Vec<bool>,            // mask[lane]
+}
+
+impl PipelineRegisters {
+    pub fn new(num_registers: usize, simd: usize) -> PipelineRegisters {
+        PipelineRegisters {
+            data: vec![vec![Scalar::I32(0); simd]; num_registers],
+            valid: vec![false; num_registers],
+            scalars: vec![],
+            counters: vec![],
+            mask: vec![true; simd],
+        }
+    }
+
+    // An element without vector data can still be present, e.g. if it was generated by the counter chain.
+    pub fn is_bubble(&self) -> bool {
+        !self.valid.iter().any(|v| *v) && self.scalars.is_empty() && self.counters.is_empty()
+    }
+}
+
+// Lane permutation of a shuffle network. Every register of the previous stage is permuted the same way.
+// A stage with a permutation and without ALUs is a pure shuffle stage.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Permutation {
+    Rotate(usize),           // lane i reads lane (i + n) % simd
+    Broadcast(usize),        // every lane reads the given lane
+    Butterfly(usize),        // lane i reads lane i ^ 2^k
+    Transpose(usize, usize), // the lanes hold a (rows, cols) matrix in row-major order, which is transposed
+    Custom(Vec<usize>)       // lane i reads lane perm[i]
+}
+
+impl Permutation {
+    // Lane of the input that ends up in `lane` of the output. 
+    pub fn source_lane(&self, lane: usize, num_simd_lanes: usize) -> usize {
+        match self {
+            Permutation::Rotate(n) => (lane + n) % num_simd_lanes,
+            Permutation::Broadcast(src) => *src,
+            Permutation::Butterfly(k) => {
+                let partner = lane ^ (1 << k);
+                if partner < num_simd_lanes { partner } else { lane }
+            },
+            Permutation::Transpose(rows, cols) => {
+                if lane < rows * cols { (lane % rows) * cols + lane / rows } else { lane }
+            },
+            Permutation::Custom(perm) => perm[lane]
+        }
+    }
+
+    pub fn apply(&self, data: &Vec<Scalar>) -> Vec<Scalar> {
+        (0..data.len()).map(|lane| data[self.source_lane(lane, data.len())].clone()).collect()
+    }
+
+    pub fn verify(&self, num_simd_lanes: usize) -> () {
+        assert!(num_simd_lanes > 0, "A shuffle network needs at least one lane.");
+        match self {
+            Permutation::Broadcast(src) => assert!(*src < num_simd_lanes, "Broadcast lane does not exist."),
+            Permutation::Transpose(rows, cols) => 
+                assert!(rows * cols <= num_simd_lanes, "Transposed matrix does not fit into the lanes."),
+            Permutation::Custom(perm) => {
+                assert_eq!(perm.len(), num_simd_lanes, "Permutation has to select a source for every lane.");
+                assert!(perm.iter().all(|src| *src < num_simd_lanes), "Permutation selects a lane that does not exist.");
+            },
+            Permutation::Butterfly(k) => assert!(*k < usize::BITS as usize && (1 << k) < num_simd_lanes, 
+                "Butterfly partner lane does not exist."),
+            Permutation::Rotate(_) => ()
+        }
+    }
+}
 
 pub struct PipelineStage {
-    pub alu_config: ALURtConfig, 
-    pub simd: usize,             // number of ALUs in the pipeline stage
-    pub register_depth: usize,   // number of target registers for each ALU
-    pub data: Vec<Vec<Scalar>>,  
+    pub alu_configs: Vec<ALURtConfig>, // one entry per ALU in the stage
+    pub timings: Vec<ALUOpTiming>,     // timing of the op configured on each ALU
+    pub simd: usize,                   // number of lanes each ALU operates on
+    pub register_depth: usize,         // number of target registers for each ALU
+    pub registers: PipelineRegisters,
+    pub written: Vec<bool>,            // registers an ALU of the stage wrote at least once
+    pub shuffle: Option<Permutation>,  // permutation applied to the inputs before the ALUs
+    pub shuffle_latency: usize,        // cycles the shuffle network adds in front of the ALUs
+    next_free: Time,                   // earliest cycle at which the stage accepts the next element
 }
 
 impl PipelineStage {
-    pub fn new(alu_config: ALURtConfig, simd: usize, register_depth: usize) -> PipelineStage {
+    pub fn new(alu_configs: Vec<ALURtConfig>, timings: Vec<ALUOpTiming>, simd: usize, register_depth: usize) -> PipelineStage {
+        assert_eq!(alu_configs.len(), timings.len());
 
         PipelineStage {
-            alu_config: alu_config,
-            data: vec![vec![Scalar::I32(0); register_depth]; simd],
+            alu_configs: alu_configs,
+            timings: timings,
+            registers: PipelineRegisters::new(register_depth, simd),
+            written: vec![false; register_depth],
             register_depth: register_depth,
             simd: simd,
+            shuffle: None,
+            shuffle_latency: 0,
+            next_free: Time::new(0),
         }
     }
 
+    pub fn with_shuffle(mut self, permutation: Permutation, latency: usize) -> PipelineStage {
+        self.shuffle = Some(permutation);
+        self.shuffle_latency = latency;
+        self
+    }
+
+    // All ALUs of a stage write the pipeline registers at the same time, so the slowest ALU sets the pace.
+    // The shuffle network sits in front of the ALUs and adds its own latency.
+    // A stage without ALUs still takes a cycle to move its registers.
+    pub fn latency(&self) -> usize {
+        let alu_latency = self.timings.iter().map(|t| t.latency).max().unwrap_or(0);
+        std::cmp::max(alu_latency + self.shuffle_latency, 1)
+    }
+
+    // An ALU that reads its own result (NEXT) cannot start on the next element before the current result 
+    // is available, so the recurrence bounds the initiation interval by the latency of the op.
+    pub fn initiation_interval(&self) -> usize {
+        self.alu_configs.iter().zip(self.timings.iter()).map(|(cfg, timing)| {
+            if cfg.reads_next() {
+                std::cmp::max(timing.initiation_interval, timing.latency)
+            } else {
+                timing.initiation_interval
+            }
+        }).max().unwrap_or(1)
+    }
+
+    // An ALU fires if an element is present and every register it reads from the previous stage is valid.
+    fn fires(alu_config: &ALURtConfig, prev_stage: &PipelineRegisters) -> bool {
+        if prev_stage.is_bubble() {
+            return false;
+        }
+        [alu_config.in_a, alu_config.in_b].iter().all(|input| match input {
+            ALUInput::PREV(i) | ALUInput::PREV_BELOW(i) | ALUInput::PREV_STRIDE(i, _, _) => 
+                prev_stage.valid.get(*i).copied().unwrap_or(false),
+            ALUInput::NEXT(_) | ALUInput::CONSTANT(_) | ALUInput::SCALAR(_) | ALUInput::COUNTER(_) => true
+        })
+    }
+
     // Returns time after pipeline stage completion
-    // The input is a Vec<Vec<Scalar>> because the PCU has multiple inputs. 
-    // For the 2nd..nth pipeline stage, the outer Vec is always of length 1.
+    // The input holds one register set per PCU input for the first pipeline stage. 
+    // For the 2nd..nth pipeline stage, it holds the registers of the previous stage.
+    // `time` is the cycle in which the element reaches this stage. The stage starts working on it once 
+    // the previous element has left the stage (initiation interval), so that successive elements overlap.
+    // A bubble does not occupy the ALUs: it clears the valid bits, but leaves the register contents and mask, 
+    // and therefore the state seen through NEXT, untouched.
+    // The ALUs of a stage work independently; each one only writes its own target register.
+    // Registers that are not the target of any ALU pass the value (and valid bit) of the same register 
+    // of the previous stage through untouched, so values can be consumed several stages later. State read 
+    // through NEXT always lives in a register some ALU of the stage writes, so the pass-through never overwrites it.
+    // If the stage has a shuffle network, the ALUs and the pass-through see the permuted registers.
+    // ALUs skip masked-off lanes, so these lanes keep their previous value and the state seen through NEXT.
+    pub fn iterate(&mut self, prev_stage: &PipelineRegisters, time: Time) -> (&PipelineRegisters, Time) {
+        let shuffled;
+        let prev_stage = match &self.shuffle {
+            Some(permutation) => {
+                shuffled = PipelineRegisters {
+                    data: prev_stage.data.iter().map(|lanes| permutation.apply(lanes)).collect(),
+                    valid: prev_stage.valid.clone(),
+                    scalars: prev_stage.scalars.clone(),
+                    counters: prev_stage.counters.clone(),
+                    mask: (0..prev_stage.mask.len())
+                        .map(|lane| prev_stage.mask[permutation.source_lane(lane, prev_stage.mask.len())])
+                        .collect()
+                };
+                &shuffled
+            },
+            None => prev_stage
+        };
+
+        let mut next_data = self.registers.clone();
+        next_data.valid.iter_mut().for_each(|v| *v = false);
+        next_data.scalars = prev_stage.scalars.clone();
+        next_data.counters = prev_stage.counters.clone();
+        if !prev_stage.is_bubble() {
+            next_data.mask = prev_stage.mask.clone();
+        }
 
-    // TODO: There are still many open questions here. For instance, we currently move values in the pipeline
-    // that are actually computed on by the ALU. We could, however, also move every value in the pipeline
-    // regardless of if it is computed on or not. The Plasticine paper does not describe how this is done. 
-    pub fn iterate(&mut self, prev_stage: &Vec<Vec<Scalar>>, time: Time) -> (&Vec<Vec<Scalar>>, Time) {
-        let mut next_data = vec![vec![Scalar::I32(0); self.data.len()]];
+        let targets: Vec<usize> = self.alu_configs.iter().map(|cfg| cfg.target).collect();
+        for reg in (0..self.register_depth).filter(|reg| !targets.contains(reg)) {
+            if let (Some(data), Some(valid)) = (prev_stage.data.get(reg), prev_stage.valid.get(reg)) {
+                next_data.data[reg] = data.clone();
+                next_data.valid[reg] = *valid;
+            }
+        }
+
+        let firing: Vec<&ALURtConfig> = self.alu_configs.iter()
+            .filter(|cfg| PipelineStage::fires(cfg, prev_stage))
+            .collect();
 
-        for idx in 0..self.simd {
+        for alu_config in firing.iter() {
+            for idx in (0..self.simd).filter(|idx| prev_stage.mask[*idx]) {
+                let lhs = self.get_input(&alu_config.in_a, prev_stage, idx);
+                let rhs = self.get_input(&alu_config.in_b, prev_stage, idx);
 
-            let lhs = self.get_input(&self.alu_config.in_a, prev_stage, idx);
-            let rhs = self.get_input(&self.alu_config.in_b, prev_stage, idx);
+                next_data.data[alu_config.target][idx] = alu_config.op.apply(&lhs, &rhs)
+            }
+            next_data.valid[alu_config.target] = true;
+            self.written[alu_config.target] = true;
+        }
+        self.registers = next_data;
 
-            next_data[idx][self.alu_config.target] = self.alu_config.op.apply(&lhs, &rhs)
+        if self.registers.is_bubble() {
+            return (&self.registers, time);
         }
-        self.data = next_data;
-        (&self.data, time + self.alu_config.op.delay() as u64)
+
+        let start = std::cmp::max(time, self.next_free);
+        self.next_free = start + self.initiation_interval() as u64;
+        (&self.registers, start + self.latency() as u64)
     }
 
-    fn get_input(&self, alu_input: &ALUInput, prev_stage: &Vec<Vec<Scalar>>, idx: usize) -> Scalar {
+    fn get_input(&self, alu_input: &ALUInput, prev_stage: &PipelineRegisters, idx: usize) -> Scalar {
         match alu_input {
-            ALUInput::NEXT(register_sel) => self.data
+            ALUInput::NEXT(register_sel) => self.registers.data
                 .get(*register_sel).expect("Error: Selected Pipeline Register Set does not exist.")
                 .get(idx).expect("Error: ALU Input NEXT({register_sel}) does not exist.")
                 .clone(),
-            ALUInput::PREV(register_sel) => prev_stage
+            ALUInput::PREV(register_sel) => prev_stage.data
                 .get(*register_sel).expect("Error: Selected Pipeline Register Set does not exist.")
                 .get(idx).expect("Error: ALU Input NEXT({register_sel}) does not exist.")
                 .clone(),
-            ALUInput::PREV_BELOW(register_sel) => prev_stage[*register_sel].get(idx+1)
+            ALUInput::PREV_BELOW(register_sel) => prev_stage.data[*register_sel].get(idx+1)
                 .expect(r#"Selected ALU Input "PREV_BELOW" does not exist."#).clone(),
-            ALUInput::CONSTANT(x) => x.clone()
+            // Masked-off lanes read as padding, so reductions over partial vectors ignore them.
+            ALUInput::PREV_STRIDE(register_sel, stride, padding) => prev_stage.data
+                .get(*register_sel).expect("Error: Selected Pipeline Register Set does not exist.")
+                .get(idx + stride)
+                .filter(|_| prev_stage.mask[idx + stride])
+                .unwrap_or(padding)
+                .clone(),
+            ALUInput::CONSTANT(x) => x.clone(),
+            ALUInput::SCALAR(scalar_sel) => prev_stage.scalars
+                .get(*scalar_sel).expect("Error: Selected scalar input does not exist.")
+                .clone(),
+            ALUInput::COUNTER(level) => prev_stage.counters
+                .get(*level).expect("Error: Selected counter does not exist.")
+                .get(idx).expect("Error: Counter lane does not exist.")
+                .clone()
         }
     }
 }
@@ -65,7 +259,7 @@ mod tests {
 
     use crate::scalar::Scalar;
     use crate::alu::{ALUInput, ALUOp, ALURtConfig};
-    use super::PipelineStage;
+    use super::{Permutation, PipelineRegisters, PipelineStage};
 
     fn prepare() -> PipelineStage {
         let alu_rt_config_1 = ALURtConfig {
@@ -74,19 +268,138 @@ mod tests {
             in_b: ALUInput::NEXT(0),
             target: 0
         };
-        PipelineStage::new(alu_rt_config_1, 1, 1)
+        PipelineStage::new(vec![alu_rt_config_1], vec![ALUOp::ADD_I32.default_timing()], 1, 1)
+    }
+
+    fn element(x: i32) -> PipelineRegisters {
+        PipelineRegisters { data: vec![vec![Scalar::I32(x)]], valid: vec![true], scalars: vec![], counters: vec![], mask: vec![true] }
     }
     
+    #[test]
+    fn permutation_test() {
+        let lanes: Vec<Scalar> = (0..6).map(Scalar::I32).collect();
+        let permuted = |p: Permutation| -> Vec<i32> {
+            p.apply(&lanes).iter().map(|x| if let Scalar::I32(x) = x { *x } else { panic!() }).collect()
+        };
+        assert_eq!(permuted(Permutation::Rotate(2)), vec![2, 3, 4, 5, 0, 1]);
+        assert_eq!(permuted(Permutation::Broadcast(3)), vec![3; 6]);
+        assert_eq!(permuted(Permutation::Butterfly(1)), vec![2, 3, 0, 1, 4, 5]);
+        // [[0, 1, 2], [3, 4, 5]] -> [[0, 3], [1, 4], [2, 5]]
+        assert_eq!(permuted(Permutation::Transpose(2, 3)), vec![0, 3, 1, 4, 2, 5]);
+    }
+
+    #[test]
+    #[should_panic(expected = "A shuffle network needs at least one lane.")]
+    fn permutation_without_lanes_test() {
+        Permutation::Rotate(1).verify(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Butterfly partner lane does not exist.")]
+    fn butterfly_beyond_lanes_test() {
+        // With 4 lanes, the farthest partner is 2 lanes away.
+        Permutation::Butterfly(1).verify(4);
+        Permutation::Butterfly(2).verify(4);
+    }
+
+    #[test]
+    #[should_panic(expected = "Butterfly partner lane does not exist.")]
+    fn butterfly_shift_overflow_test() {
+        Permutation::Butterfly(usize::BITS as usize).verify(4);
+    }
+
     #[test]
     fn pipeline_holds_state_test() {
         let t_0 = Time::new(0);
         let mut pl = prepare();
-        let input = vec![vec![Scalar::I32(1)]];
+        let input = element(1);
 
         let (_, t_1) = pl.iterate(&input, t_0);
-        assert_eq!(pl.data[0][0], Scalar::I32(1));
+        assert_eq!(pl.registers.data[0][0], Scalar::I32(1));
         let _ = pl.iterate(&input, t_1);
-        assert_eq!(pl.data[0][0], Scalar::I32(2));
+        assert_eq!(pl.registers.data[0][0], Scalar::I32(2));
+    }
+
+    #[test]
+    fn bubble_keeps_state_test() {
+        let mut pl = prepare();
+        let (_, t_1) = pl.iterate(&element(1), Time::new(0));
+        assert!(pl.registers.valid[0]);
+
+        let (regs, t_2) = pl.iterate(&PipelineRegisters::new(1, 1), t_1);
+        assert!(regs.is_bubble());
+        assert_eq!(t_2, t_1);
+        assert_eq!(pl.registers.data[0][0], Scalar::I32(1));
+
+        let _ = pl.iterate(&element(1), t_2);
+        assert!(pl.registers.valid[0]);
+        assert_eq!(pl.registers.data[0][0], Scalar::I32(2));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn parallel_alus_test() {
+        let add = ALURtConfig { op: ALUOp::ADD_I32, in_a: ALUInput::PREV(0), in_b: ALUInput::PREV(1), target: 0 };
+        let mul = ALURtConfig { op: ALUOp::MUL_I32, in_a: ALUInput::PREV(0), in_b: ALUInput::PREV(1), target: 1 };
+        let mut pl = PipelineStage::new(
+            vec![add, mul], vec![ALUOp::ADD_I32.default_timing(), ALUOp::MUL_I32.default_timing()], 1, 2);
+
+        let input = PipelineRegisters { data: vec![vec![Scalar::I32(3)], vec![Scalar::I32(4)]], valid: vec![true, true], scalars: vec![], counters: vec![], mask: vec![true] };
+        let (regs, t) = pl.iterate(&input, Time::new(0));
+        assert_eq!(regs.data, vec![vec![Scalar::I32(7)], vec![Scalar::I32(12)]]);
+        assert_eq!(regs.valid, vec![true, true]);
+        assert_eq!(t, Time::new(ALUOp::MUL_I32.default_timing().latency as u64));
+    }
+
+    #[test]
+    fn pass_through_test() {
+        let add = ALURtConfig { op: ALUOp::ADD_I32, in_a: ALUInput::PREV(0), in_b: ALUInput::PREV(1), target: 0 };
+        let mut pl = PipelineStage::new(vec![add], vec![ALUOp::ADD_I32.default_timing()], 1, 3);
+
+        let input = PipelineRegisters { data: vec![vec![Scalar::I32(3)], vec![Scalar::I32(4)]], valid: vec![true, true], scalars: vec![], counters: vec![], mask: vec![true] };
+        let (regs, _) = pl.iterate(&input, Time::new(0));
+        // Register 1 is not written by an ALU and passes the input through, register 2 has no source.
+        assert_eq!(regs.data[0..2].to_vec(), vec![vec![Scalar::I32(7)], vec![Scalar::I32(4)]]);
+        assert_eq!(regs.valid, vec![true, true, false]);
+    }
+
+    #[test]
+    fn pipeline_respects_initiation_interval_test() {
+        let mut pl = prepare();
+        pl.timings[0].initiation_interval = 3;
+        let input = element(1);
+
+        let (_, t_1) = pl.iterate(&input, Time::new(0));
+        assert_eq!(t_1, Time::new(1));
+        // The second element arrives one cycle later, but has to wait for the stage to become free.
+        let (_, t_2) = pl.iterate(&input, Time::new(1));
+        assert_eq!(t_2, Time::new(4));
+    }
+
+    #[test]
+    fn late_element_starts_on_arrival_test() {
+        let mut pl = prepare();
+        let input = element(1);
+
+        let _ = pl.iterate(&input, Time::new(0));
+        // Nothing arrives for a while: the stage is idle and starts on the next element right away.
+        let (_, t_2) = pl.iterate(&input, Time::new(10));
+        assert_eq!(t_2, Time::new(11));
+        assert_eq!(pl.registers.data[0][0], Scalar::I32(2));
+    }
+
+    #[test]
+    fn masked_lanes_keep_state_test() {
+        let mut pl = PipelineStage::new(vec![ALURtConfig {
+            op: ALUOp::ADD_I32, in_a: ALUInput::PREV(0), in_b: ALUInput::NEXT(0), target: 0
+        }], vec![ALUOp::ADD_I32.default_timing()], 2, 1);
+
+        let full = PipelineRegisters { data: vec![vec![Scalar::I32(1), Scalar::I32(1)]], valid: vec![true], scalars: vec![], counters: vec![], mask: vec![true, true] };
+        let tail = PipelineRegisters { mask: vec![true, false], ..full.clone() };
+
+        let (regs, t) = pl.iterate(&full, Time::new(0));
+        assert_eq!(regs.data[0], vec![Scalar::I32(1), Scalar::I32(1)]);
+        let (regs, _) = pl.iterate(&tail, t);
+        assert_eq!(regs.data[0], vec![Scalar::I32(2), Scalar::I32(1)]);
+        assert_eq!(regs.mask, vec![true, false]);
+    }
+}
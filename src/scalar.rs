@@ -1,9 +1,23 @@
-#[derive(PartialEq, Debug, Clone, Copy)]
+use dam::types::DAMType;
+
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
 pub enum Scalar {
-    I32(i32), FP32(f32), Bit(bool), DontCare, Empty
+    I32(i32), FP32(f32), Bit(bool), DontCare, #[default] Empty
+}
+
+// Scalars travel on their own over the scalar network.
+impl DAMType for Scalar {
+    fn dam_size(&self) -> usize {
+        match self {
+            Scalar::DontCare | Scalar::Empty => 0,
+            _ => self.width()
+        }
+    }
 }
 
 impl Scalar {
+    pub const WIDTHS: [usize; 2] = [1, 32]; // widths in bits of the types width() knows
+
     pub fn width(&self) -> usize {
         match self {
             Scalar::Bit(_) => 1,
@@ -1,16 +1,77 @@
-use std::collections::HashMap;
+use std::{collections::{HashMap, HashSet, VecDeque}, error::Error, fmt, path::Path, sync::{Arc, Mutex}};
 
-use dam::{channel::{ChannelElement, PeekResult, Receiver, Sender}, context::Context, dam_macros::context_macro, structures::{ContextInfo, Time}};
+use dam::{channel::{ChannelElement, PeekResult, Receiver, Sender}, context::Context, dam_macros::context_macro, structures::{ContextInfo, Time}, types::DAMType};
 
-use crate::pcu::PCUData;
+use serde::Deserialize;
+
+use crate::{channel_utils::{wait_for_earliest, wait_for_producer, InputWait}, control::{poll_config_load, reconfigured_at, ConfigLoad, ControlPort}, interconnect::{PacketRouting, RoutingAlgorithm, ESCAPE_VC}, pcu::PCUData, scalar::Scalar, types::Heading};
 
 pub enum SwitchMode {
     SingleEnqueueSingleDequeue, // 1. Dequeue and enqueue exactly one element per clock cycle.
     MultiEnqueueSingleDequeue,  // 2. Dequeue one element per clock cycle, enqueue it to all selected outputs.
-    MultiEnqueueMultiDequeue    // 3. Dequeue from all inputs, enqueue to all outputs.
+    MultiEnqueueMultiDequeue,   // 3. Dequeue from all inputs, enqueue to all outputs.
+    PipelinedBroadcast          // 4. Like 2, but every output has a register in place of its output buffer. A broadcast 
+                                //    writes the registers of all selected outputs in one cycle, and the registers drive 
+                                //    the outputs while the next element is dequeued, at the cost of one cycle of latency. 
+                                //    The switch stalls while a selected register still holds an element.
+}
+
+// Cycles an element takes from an input to an output of the switch.
+// All models except Closure can be loaded from a config file, e.g. {"Constant": 2} or {"Table": [[1, 2], [2, 1]]}.
+#[derive(Deserialize)]
+pub enum DelayModel {
+    Constant(usize),
+    Table(Vec<Vec<usize>>), // table[in][out]
+    // Ports are placed on a grid. The delay grows with the manhattan distance between input and output.
+    Distance {
+        input_positions: Vec<(i32, i32)>,
+        output_positions: Vec<(i32, i32)>,
+        base: usize,
+        per_unit: usize
+    },
+    #[serde(skip)]
+    Closure(Box<dyn Fn(usize, usize) -> usize + Send + Sync>)
 }
 
-type DelayFunction = fn(usize, usize) -> usize;
+impl DelayModel {
+    pub fn delay(&self, input: usize, output: usize) -> usize {
+        match self {
+            DelayModel::Constant(delay) => *delay,
+            DelayModel::Table(table) => table[input][output],
+            DelayModel::Distance { input_positions, output_positions, base, per_unit } => {
+                let (x_in, y_in) = input_positions[input];
+                let (x_out, y_out) = output_positions[output];
+                let distance = (x_in - x_out).unsigned_abs() + (y_in - y_out).unsigned_abs();
+                base + per_unit * distance as usize
+            },
+            DelayModel::Closure(delay) => delay(input, output)
+        }
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(Self::from_json(&json)?)
+    }
+
+    // The table and the port geometry have to cover every input and output.
+    fn verify(&self, num_inputs: usize, num_outputs: usize) -> () {
+        match self {
+            DelayModel::Table(table) => {
+                assert_eq!(table.len(), num_inputs, "Delay table needs a row per input.");
+                assert!(table.iter().all(|row| row.len() == num_outputs), "Delay table needs a column per output.");
+            },
+            DelayModel::Distance { input_positions, output_positions, .. } => {
+                assert_eq!(input_positions.len(), num_inputs, "Every input needs a position.");
+                assert_eq!(output_positions.len(), num_outputs, "Every output needs a position.");
+            },
+            DelayModel::Constant(_) | DelayModel::Closure(_) => ()
+        }
+    }
+}
 
 pub struct HwConfig {
     pub simd: usize, 
@@ -18,17 +79,194 @@ pub struct HwConfig {
     pub num_inputs: usize,
     pub num_outputs: usize,
     pub mode: SwitchMode,
-    pub delay: DelayFunction // delay_table[in, out -> delay
+    pub delay: DelayModel,
+    pub input_buffer_depth: usize,  // elements per virtual channel a producer may send before it has to wait for a credit
+    pub output_buffer_depth: usize, // routed elements per virtual channel an output holds while it waits for credits
+    pub num_vcs: usize,             // virtual channels per port, each with its own input and output buffers
     // todo: Add parameterizable routing restrictions? 
 }
 
+// Credit-based flow control between switches: a consumer returns one credit for a virtual channel whenever 
+// an element leaves the input buffer of that channel, and a producer only sends on a virtual channel while 
+// it holds credits for it. 
+// The consumer moves arriving elements into the buffers of their virtual channels right away, so the link 
+// itself does not have to hold them. The credit channel needs a capacity of `num_vcs * input_buffer_depth`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Credit {
+    pub vc: usize
+}
+
+impl DAMType for Credit {
+    fn dam_size(&self) -> usize {
+        1
+    }
+}
+
+// Credits returned by the consumer of an output, together with the consumer's input buffer depth.
+pub struct CreditLink {
+    pub credits: Receiver<Credit>,
+    buffer_depth: usize
+}
+
+impl CreditLink {
+    // The producer starts out with a credit for every slot of the consumer's input buffers.
+    pub fn new(credits: Receiver<Credit>, consumer: &HwConfig) -> CreditLink {
+        CreditLink { credits: credits, buffer_depth: consumer.input_buffer_depth }
+    }
+}
+
+impl HwConfig {
+    // A link moves `simd` lanes of `datatype_width` bits per cycle. Wider messages, i.e. more lanes or wider 
+    // scalars, are serialized over several cycles (see Switch::transfer_cycles).
+    pub fn validate(&self) -> () {
+        assert!(self.simd > 0, "Switch links need at least one lane.");
+        assert!(Scalar::WIDTHS.contains(&self.datatype_width), "Switch lanes have to be as wide as a scalar type.");
+        assert!(self.input_buffer_depth > 0 && self.output_buffer_depth > 0, "Switch buffers need a positive depth.");
+        assert!(self.num_vcs > 0, "Switch ports need at least one virtual channel.");
+        self.delay.verify(self.num_inputs, self.num_outputs);
+    }
+
+    // A producer, e.g. a PCU, sends vectors of `simd` scalars of `datatype_width` bits. The lanes of the link 
+    // have to carry its scalars, and a link wider than its vectors would never be used in full.
+    pub fn validate_producer(&self, simd: usize, datatype_width: usize) -> () {
+        assert_eq!(datatype_width, self.datatype_width, "Switch lanes do not match the scalars of the producer.");
+        assert!(self.simd <= simd, "Switch link is wider than the vectors of the producer.");
+    }
+}
+
+// A switch either forwards by a static routing table (circuit switching), or routes every packet 
+// by its destination (packet switching), in which case the routing table is ignored.
+// `vc_routes` maps an (input, VC) pair to (output, VC) pairs. Pairs it does not contain fall back to 
+// `routing_table` and keep their virtual channel. Data on an input neither of them routes is handled by `unrouted`.
+#[derive(Clone, Debug, Default)]
 pub struct RtConfig {
     pub routing_table: HashMap<usize, Vec<usize>>, // routing_table[in] -> out
+    pub vc_routes: HashMap<(usize, usize), Vec<(usize, usize)>>, // vc_routes[(in, vc)] -> (out, vc)
+    pub packet_routing: Option<PacketRouting>,
+    pub unrouted: UnroutedPolicy,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnroutedPolicy {
+    #[default]
+    Error,                // report a SwitchError to the monitor and stop the switch, which fails the run
+    Drop,                 // drop the element and count it
+    DefaultOutput(usize)  // send the element to this output, on its virtual channel
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SwitchErrorKind {
+    UnroutedInput { vc: usize }
+}
+
+// A failure of a switch. The switch reports it to its monitor, then stops with it, so that the run does not pass.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SwitchError {
+    pub switch: String,
+    pub port: usize,
+    pub kind: SwitchErrorKind
+}
+
+impl fmt::Display for SwitchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            SwitchErrorKind::UnroutedInput { vc } => write!(f, "{}: data on unrouted input {} (VC {})", self.switch, self.port, vc)
+        }
+    }
+}
+
+impl Error for SwitchError {}
+
+#[derive(Debug, Default)]
+struct MonitorState {
+    dropped: HashMap<String, usize>, // dropped[switch]
+    errors: Vec<SwitchError>
+}
+
+// Collects what switches and the global network report during a simulation, so that it can be checked once 
+// the run finished.
+// Clones share their state, so several switches can report to the same monitor.
+#[derive(Clone, Debug, Default)]
+pub struct SwitchMonitor {
+    state: Arc<Mutex<MonitorState>>
+}
+
+impl SwitchMonitor {
+    // Elements a switch dropped under UnroutedPolicy::Drop.
+    pub fn dropped(&self, switch: &str) -> usize {
+        self.state.lock().unwrap().dropped.get(switch).copied().unwrap_or(0)
+    }
+
+    pub fn errors(&self) -> Vec<SwitchError> {
+        self.state.lock().unwrap().errors.clone()
+    }
+
+    // Data on `input` that no route of `switch` covers: counted as dropped, or an error that stops the switch.
+    pub fn report_unrouted(&self, policy: UnroutedPolicy, switch: &str, input: usize, vc: usize) {
+        match policy {
+            UnroutedPolicy::Error => {
+                let error = SwitchError { switch: switch.to_string(), port: input, kind: SwitchErrorKind::UnroutedInput { vc: vc } };
+                self.record_error(error.clone());
+                panic!("{}", error);
+            },
+            UnroutedPolicy::Drop | UnroutedPolicy::DefaultOutput(_) => self.record_drop(switch)
+        }
+    }
+
+    fn record_drop(&self, switch: &str) {
+        *self.state.lock().unwrap().dropped.entry(switch.to_string()).or_insert(0) += 1;
+    }
+
+    fn record_error(&self, error: SwitchError) {
+        self.state.lock().unwrap().errors.push(error);
+    }
+}
+
+impl RtConfig {
+    pub fn validate(&self, hw_config: &HwConfig) -> () {
+        for (input, outputs) in self.routing_table.iter() {
+            assert!(*input < hw_config.num_inputs, "Routing table routes an input that does not exist.");
+            assert!(!outputs.is_empty(), "Routing table routes an input to no output. Leave it out to use the unrouted policy.");
+            assert!(outputs.iter().all(|output| *output < hw_config.num_outputs), "Routing table routes to an output that does not exist.");
+        }
+        for ((input, vc), targets) in self.vc_routes.iter() {
+            assert!(*input < hw_config.num_inputs && *vc < hw_config.num_vcs, "Routing table routes an input that does not exist.");
+            assert!(!targets.is_empty(), "Routing table routes an input to no output. Leave it out to use the unrouted policy.");
+            assert!(targets.iter().all(|(output, vc)| *output < hw_config.num_outputs && *vc < hw_config.num_vcs), 
+                "Routing table routes to an output that does not exist.");
+        }
+        if let UnroutedPolicy::DefaultOutput(output) = self.unrouted {
+            assert!(output < hw_config.num_outputs, "Default output does not exist.");
+        }
+        if let Some(routing) = &self.packet_routing {
+            if routing.algorithm == RoutingAlgorithm::MinimalAdaptive {
+                assert!(hw_config.num_vcs > ESCAPE_VC, "Adaptive routing needs an escape virtual channel.");
+            }
+            assert!(routing.inputs.values().all(|input| *input < hw_config.num_inputs), "Packet routing uses an input that does not exist.");
+            assert!(routing.outputs.values().all(|output| *output < hw_config.num_outputs), "Packet routing uses an output that does not exist.");
+        }
+    }
 }
 
 pub struct RtData {
     receivers: Vec<Receiver<PCUData>>,
-    senders: Vec<Sender<PCUData>>
+    senders: Vec<Sender<PCUData>>,
+    input_buffers: Vec<Vec<VecDeque<ChannelElement<PCUData>>>>,  // input_buffers[in][vc]
+    output_buffers: Vec<Vec<VecDeque<ChannelElement<PCUData>>>>, // output_buffers[out][vc], stamped with their arrival time
+    credit_links: Vec<Option<CreditLink>>,       // credit_links[out], None if the consumer does not use credits
+    credits: Vec<Vec<usize>>,                    // credits[out][vc]
+    credit_returns: Vec<Option<Sender<Credit>>>, // credit_returns[in], None if the producer does not use credits
+    registers: Vec<Option<(usize, ChannelElement<PCUData>)>>, // PipelinedBroadcast: registers[out] with the input of its element
+    register_free: Vec<Time>,                    // PipelinedBroadcast: register_free[out], cycle from which it can be written again
+    link_free: Vec<Time>,                        // PipelinedBroadcast: link_free[out], cycle from which it can start the next element
+    link_vcs: Vec<Option<usize>>,                // link_vcs[in], the only virtual channel an input without credits carries
+    config_port: Option<Receiver<ConfigLoad<RtConfig>>>,
+    control_ports: Vec<ControlPort>,
+    last_sent: Time,                             // time the latest element left on any output
+    name: String,
+    monitor: SwitchMonitor,
+    end_of_stream_received: Vec<HashSet<Heading>>, // packet switching: end_of_stream_received[in]
+    end_of_stream_sent: Vec<HashSet<Heading>>      // packet switching: end_of_stream_sent[out]
 }
 
 #[context_macro]
@@ -44,13 +282,33 @@ impl Switch {
 
         assert_eq!(hw_config.num_inputs, receivers.len());
         assert_eq!(hw_config.num_outputs, senders.len());
+        hw_config.validate();
+        rt_config.validate(&hw_config);
+        let (num_inputs, num_outputs, num_vcs) = (hw_config.num_inputs, hw_config.num_outputs, hw_config.num_vcs);
+        let buffers = |num_ports: usize| (0..num_ports).map(|_| (0..num_vcs).map(|_| VecDeque::new()).collect()).collect();
 
         let switch = Switch { 
             hw_config: hw_config, 
             rt_config: rt_config, 
             rt_data: RtData { 
                 receivers: receivers,
-                senders: senders
+                senders: senders,
+                input_buffers: buffers(num_inputs),
+                output_buffers: buffers(num_outputs),
+                credit_links: (0..num_outputs).map(|_| None).collect(),
+                credits: vec![vec![0; num_vcs]; num_outputs],
+                credit_returns: (0..num_inputs).map(|_| None).collect(),
+                registers: (0..num_outputs).map(|_| None).collect(),
+                register_free: vec![Time::new(0); num_outputs],
+                link_free: vec![Time::new(0); num_outputs],
+                link_vcs: vec![None; num_inputs],
+                config_port: None,
+                control_ports: vec![],
+                last_sent: Time::new(0),
+                name: String::from("switch"),
+                monitor: SwitchMonitor::default(),
+                end_of_stream_received: vec![HashSet::new(); num_inputs],
+                end_of_stream_sent: vec![HashSet::new(); num_outputs]
             }, 
             context_info: ContextInfo::default() 
         };
@@ -60,91 +318,391 @@ impl Switch {
         switch
     }
 
-    fn get_first_available_receiver_inputs(&self) -> Vec<usize>{
-        // TODO: implement additional behavior: if all channels are empty at the current timestamp, advance time and continue.
-        let minimal_input;
-        loop {
-            let mut peek_results = self.rt_data.receivers.iter()
-                .enumerate()
-                .map(|(i, r)|(i, r.peek()))
-                .filter(|(_, x)| !matches!(x, PeekResult::Closed))
-                .peekable();
+    // `credit_returns[in]` returns credits to the producer of an input, `credit_links[out]` receives them from 
+    // the consumer of an output.
+    pub fn with_credit_ports(mut self, credit_returns: Vec<Option<Sender<Credit>>>, credit_links: Vec<Option<CreditLink>>) -> Switch {
+        assert_eq!(credit_returns.len(), self.hw_config.num_inputs);
+        assert_eq!(credit_links.len(), self.hw_config.num_outputs);
+        self.rt_data.credits = credit_links.iter()
+            .map(|link| vec![link.as_ref().map(|l| l.buffer_depth).unwrap_or(0); self.hw_config.num_vcs])
+            .collect();
+        self.rt_data.credit_returns = credit_returns;
+        self.rt_data.credit_links = credit_links;
+        self.rt_data.credit_returns.iter().flatten().for_each(|s| s.attach_sender(&self));
+        self.rt_data.credit_links.iter().flatten().for_each(|l| l.credits.attach_receiver(&self));
+        self
+    }
+
+    // The switch routes nothing before a token arrived on every control input. Once all inputs closed and 
+    // the last element left, it sends a done token on every control output.
+    pub fn with_control_ports(mut self, control_ports: Vec<ControlPort>) -> Switch {
+        control_ports.iter().for_each(|p| p.attach(&self));
+        self.rt_data.control_ports = control_ports;
+        self
+    }
+
+    // The name the switch reports under.
+    pub fn with_name(mut self, name: &str) -> Switch {
+        self.rt_data.name = name.to_string();
+        self
+    }
+
+    // Share a monitor with other switches. By default, every switch has its own.
+    pub fn with_monitor(mut self, monitor: SwitchMonitor) -> Switch {
+        self.rt_data.monitor = monitor;
+        self
+    }
+
+    pub fn monitor(&self) -> SwitchMonitor {
+        self.rt_data.monitor.clone()
+    }
+
+    // Config loads on this port replace the routing while the switch runs.
+    pub fn with_config_port(mut self, config_port: Receiver<ConfigLoad<RtConfig>>) -> Switch {
+        config_port.attach_receiver(&self);
+        self.rt_data.config_port = Some(config_port);
+        self
+    }
+
+    // Elements routed before a config load arrived keep their outputs. While the switch reconfigures, 
+    // it routes nothing.
+    // Takes the loads that arrived by now. The switch has no pipeline to drain: it is done with its work at 
+    // `busy_until`, and the elements in its buffers stay there while it reconfigures.
+    fn load_config(&mut self, busy_until: Time) {
+        let Some(port) = &self.rt_data.config_port else {
+            return;
+        };
+        let mut busy_until = busy_until;
+        while let Some(load) = poll_config_load(port, &self.time, &self.time.tick()) {
+            load.data.config.validate(&self.hw_config);
+            busy_until = reconfigured_at(&load, busy_until);
+            if busy_until > self.time.tick() {
+                self.time.advance(busy_until.clone());
+            }
+            self.rt_config = load.data.config;
+        }
+    }
+
+    // Packet switching: no packet with this heading is left in the switch, and none can arrive anymore. 
+    // The injection port has to close, every other input has to close or mark the end of the heading.
+    fn heading_ended(&self, routing: &PacketRouting, heading: Heading) -> bool {
+        let inputs_ended = (0..self.hw_config.num_inputs).all(|input| {
+            matches!(self.rt_data.receivers[input].peek(), PeekResult::Closed) 
+                || (!routing.is_injection_port(input) && self.rt_data.end_of_stream_received[input].contains(&heading))
+        });
+        let registered = self.rt_data.registers.iter().flatten().map(|(_, element)| element);
+        let buffered = self.rt_data.input_buffers.iter().chain(self.rt_data.output_buffers.iter())
+            .flatten()
+            .flatten()
+            .chain(registered)
+            .any(|element| routing.heading(element.data.dest.expect("Packet without a destination.")) == heading);
+        inputs_ended && !buffered
+    }
+
+    // Packet switching: marks the end of a heading on a link once no more packets can arrive with it at the 
+    // next switch. The marker follows the last element the switch sent, but takes no cycles on the link. 
+    // Nothing goes onto the ejection port, which closes with the switch.
+    fn signal_end_of_stream(&mut self) {
+        let Some(routing) = &self.rt_config.packet_routing else {
+            return;
+        };
+        let ended: Vec<Heading> = Heading::ALL.into_iter().filter(|heading| self.heading_ended(routing, *heading)).collect();
+        for output in (0..self.hw_config.num_outputs).filter(|output| !routing.is_ejection_port(*output)) {
+            for heading in Heading::ALL {
+                if self.rt_data.end_of_stream_sent[output].contains(&heading) 
+                    || !routing.headings_into(output, heading).iter().all(|h| ended.contains(h)) {
+                    continue;
+                }
+                let time = std::cmp::max(self.rt_data.last_sent.clone(), self.time.tick());
+                let marker = ChannelElement::new(time, PCUData::end_of_stream_marker(heading));
+                self.rt_data.senders[output].enqueue(&self.time, marker).unwrap();
+                self.rt_data.end_of_stream_sent[output].insert(heading);
+            }
+        }
+    }
 
-            if peek_results.peek().is_none() {
+    // The links of a mesh form cycles, so they never close. A packet-switched switch stops once every heading 
+    // ended instead. By then, it marked the end of every heading on its links.
+    fn network_drained(&self) -> bool {
+        match &self.rt_config.packet_routing {
+            Some(routing) => Heading::ALL.iter().all(|heading| self.heading_ended(routing, *heading)),
+            None => false
+        }
+    }
+
+    // Waits for the earliest element on any input and returns the inputs it is available on.
+    fn get_first_available_receiver_inputs(&mut self) -> Vec<usize> {
+        loop {
+            self.signal_end_of_stream();
+            if self.network_drained() {
                 return vec![];
             }
+            match wait_for_earliest(&self.rt_data.receivers, &self.time) {
+                InputWait::Available(inputs) => return inputs,
+                InputWait::Closed => return vec![],
+                InputWait::Waited => ()
+            }
+        }
+    }
+
+    // Bits a link moves per cycle.
+    pub fn link_bandwidth(&self) -> usize {
+        self.hw_config.simd * self.hw_config.datatype_width
+    }
+
+    // Cycles a message occupies a link. Messages wider than the link are split into several flits.
+    pub fn transfer_cycles(&self, data: &PCUData) -> usize {
+        std::cmp::max(data.dam_size().div_ceil(self.link_bandwidth()), 1)
+    }
 
-            let timing_of_inputs = peek_results.map(|(r, x)| {
-                match x {
-                PeekResult::Something(ChannelElement { time: t, data: _ }) => (r, x, t),
-                PeekResult::Nothing(t) => (r, x, t),
-                _ => panic!("This should always be something.")
-            }});
-
-            let (min, first_channels) = timing_of_inputs.fold((Time::infinite(), Vec::new()), |(min, mut vec), el| {
-                let (r, pr, t) = el;
-                if t < min {
-                    vec.clear();
-                    vec.push((r, pr));
-                    (t.clone(), vec)
-                } else if t == min {
-                    vec.push((r, pr));
-                    (min, vec)
-                } else {
-                    (min, vec)
+    // Collects the credits that arrived up to now. A consumer that lags behind may still return a credit 
+    // for a cycle we already got to, so wait for it first.
+    fn collect_credits(&mut self, output: usize) {
+        if let Some(link) = &self.rt_data.credit_links[output] {
+            while let PeekResult::Something(ChannelElement { time, data: _ }) = wait_for_producer(&link.credits, &self.time) {
+                if time > self.time.tick() {
+                    break;
+                }
+                let credit = link.credits.dequeue(&self.time).unwrap();
+                self.rt_data.credits[output][credit.data.vc] += 1;
+            }
+        }
+    }
+
+    // Moves elements from the output buffers onto the link, oldest first, as long as the consumer has room 
+    // for them in their virtual channel.
+    fn send_buffered(&mut self, output: usize) {
+        self.collect_credits(output);
+        let uses_credits = self.rt_data.credit_links[output].is_some();
+        loop {
+            let buffers = &self.rt_data.output_buffers[output];
+            let credits = &self.rt_data.credits[output];
+            let next_vc = (0..self.hw_config.num_vcs)
+                .filter(|vc| !buffers[*vc].is_empty() && (!uses_credits || credits[*vc] > 0))
+                .min_by_key(|vc| buffers[*vc].front().unwrap().time.clone());
+            let Some(vc) = next_vc else {
+                return;
+            };
+            if uses_credits {
+                self.rt_data.credits[output][vc] -= 1;
+            }
+            let element = self.rt_data.output_buffers[output][vc].pop_front().unwrap();
+            let time = std::cmp::max(element.time, self.time.tick());
+            self.rt_data.last_sent = std::cmp::max(self.rt_data.last_sent.clone(), time.clone());
+            self.rt_data.senders[output].enqueue(&self.time, ChannelElement::new(time, element.data)).unwrap();
+        }
+    }
+
+    // PipelinedBroadcast: the register of an output drives the link once the link is free and the consumer has 
+    // room. The register can take the next element in the cycle the last flit of its current one leaves.
+    fn drive_register(&mut self, output: usize) {
+        self.collect_credits(output);
+        let vc = match &self.rt_data.registers[output] {
+            Some((_, element)) => element.data.vc,
+            None => return
+        };
+        let uses_credits = self.rt_data.credit_links[output].is_some();
+        if uses_credits {
+            if self.rt_data.credits[output][vc] == 0 {
+                return;
+            }
+            self.rt_data.credits[output][vc] -= 1;
+        }
+        let (input, element) = self.rt_data.registers[output].take().unwrap();
+        let transfer_cycles = self.transfer_cycles(&element.data) as u64;
+        let start = std::cmp::max(std::cmp::max(element.time, self.rt_data.link_free[output].clone()), self.time.tick());
+        let arrival = start.clone() + self.hw_config.delay.delay(input, output) as u64 + transfer_cycles - 1;
+        self.rt_data.register_free[output] = start.clone() + transfer_cycles - 1;
+        self.rt_data.link_free[output] = start + transfer_cycles;
+        self.rt_data.last_sent = std::cmp::max(self.rt_data.last_sent.clone(), arrival.clone());
+        self.rt_data.senders[output].enqueue(&self.time, ChannelElement::new(arrival, element.data)).unwrap();
+    }
+
+    // Blocks until the consumer of an output returned a credit, then sends what the credit allows.
+    fn wait_for_credit(&mut self, output: usize) {
+        let link = self.rt_data.credit_links[output].as_ref().expect("Output buffer without credits cannot fill up.");
+        let credit = link.credits.dequeue(&self.time).expect("Consumer closed its credits while elements are waiting.");
+        self.rt_data.credits[output][credit.data.vc] += 1;
+        self.send_buffered(output);
+        self.drive_register(output);
+    }
+
+    fn has_output_room(&self, output: usize, vc: usize) -> bool {
+        match self.hw_config.mode {
+            SwitchMode::PipelinedBroadcast => self.rt_data.registers[output].is_none() && self.rt_data.register_free[output] <= self.time.tick(),
+            _ => self.rt_data.output_buffers[output][vc].len() < self.hw_config.output_buffer_depth
+        }
+    }
+
+    fn input_buffers_empty(&self) -> bool {
+        self.rt_data.input_buffers.iter().flatten().all(|buffer| buffer.is_empty())
+    }
+
+    // Moves the next element of an input link into the buffer of its virtual channel. An end-of-stream marker 
+    // only gets recorded.
+    fn take_input(&mut self, input: usize) {
+        let element = self.rt_data.receivers[input].dequeue(&self.time).unwrap();
+        if let Some(heading) = element.data.end_of_stream {
+            self.rt_data.end_of_stream_received[input].insert(heading);
+            return;
+        }
+        if self.rt_data.credit_returns[input].is_none() {
+            let vc = *self.rt_data.link_vcs[input].get_or_insert(element.data.vc);
+            assert_eq!(vc, element.data.vc, "A link without credits carries a single virtual channel, or a full one would block the others.");
+        }
+        self.rt_data.input_buffers[input][element.data.vc].push_back(element);
+    }
+
+    // Takes every element that arrived up to now, as long as its virtual channel has room. With credits 
+    // there always is room. Without, a full virtual channel holds up its link, which is why such a link may 
+    // only carry one virtual channel.
+    fn fill_input_buffers(&mut self) {
+        for input in 0..self.hw_config.num_inputs {
+            while let PeekResult::Something(ChannelElement { time, data }) = self.rt_data.receivers[input].peek() {
+                assert!(data.vc < self.hw_config.num_vcs, "Received data on a virtual channel the switch does not have.");
+                let full = data.end_of_stream.is_none() && self.rt_data.input_buffers[input][data.vc].len() >= self.hw_config.input_buffer_depth;
+                if time > self.time.tick() || full {
+                    break;
+                }
+                self.take_input(input);
+            }
+        }
+    }
+
+    // Outputs and virtual channels the oldest element of an input virtual channel continues on.
+    fn route(&self, input: usize, vc: usize) -> Vec<(usize, usize)> {
+        let element = self.rt_data.input_buffers[input][vc].front().expect("Routing an empty virtual channel.");
+        match &self.rt_config.packet_routing {
+            Some(routing) => {
+                let dest = element.data.dest.expect("Packet without a destination.");
+                let has_room = |output: usize, vc: usize| self.has_output_room(output, vc);
+                vec![routing.route(dest, vc, &has_room)]
+            },
+            None => match self.rt_config.vc_routes.get(&(input, vc)) {
+                Some(targets) => targets.clone(),
+                None => match (self.rt_config.routing_table.get(&input), self.rt_config.unrouted) {
+                    (Some(outputs), _) => outputs.iter().map(|output| (*output, vc)).collect(),
+                    (None, UnroutedPolicy::DefaultOutput(output)) => vec![(output, vc)],
+                    (None, UnroutedPolicy::Drop | UnroutedPolicy::Error) => vec![]
+                }
+            }
+        }
+    }
+
+    // VC allocation: of the input virtual channels whose oldest element has room in every output virtual 
+    // channel it goes to, the one whose element waited longest moves on. Ties go to the lowest input and VC.
+    fn allocate(&self) -> Option<(usize, usize, Vec<(usize, usize)>)> {
+        let mut winner: Option<(Time, usize, usize, Vec<(usize, usize)>)> = None;
+        for input in 0..self.hw_config.num_inputs {
+            for vc in 0..self.hw_config.num_vcs {
+                let Some(element) = self.rt_data.input_buffers[input][vc].front() else {
+                    continue;
+                };
+                if winner.as_ref().is_some_and(|(time, ..)| *time <= element.time) {
+                    continue;
+                }
+                let targets = self.route(input, vc);
+                if targets.iter().all(|(output, out_vc)| self.has_output_room(*output, *out_vc)) {
+                    winner = Some((element.time.clone(), input, vc, targets));
                 }
-            });
-
-            let avail_channels: Vec<_> = first_channels
-                .iter()
-                .filter(|(_, x)| matches!(x, PeekResult::Something(_)))
-                .map(|(x, _)| {*x})
-                .collect();
-
-            if !avail_channels.is_empty() {
-                minimal_input = avail_channels;
-                break;
-            } else if min >= self.time.tick() {
-                // All channels have no data at the current clock cycle. Advance clock. 
-                // This is needed because DAM's peek() caches the last result.
-                self.time.incr_cycles(1);
             }
         }
-        return minimal_input
+        winner.map(|(_, input, vc, targets)| (input, vc, targets))
     }
 
-    fn single_deque(&self, multi_enqueue: bool) -> Result<(), &str> {
-        let rdy_idx = *self.get_first_available_receiver_inputs().split_first().ok_or("All inputs closed.")?.0;
-        let input = self.rt_data.receivers[rdy_idx].dequeue(&self.time).unwrap();
-        let data = input.data;
+    fn single_deque(&mut self, multi_enqueue: bool, output_registers: bool) -> Result<(), &'static str> {
+        for output in 0..self.hw_config.num_outputs {
+            self.send_buffered(output);
+            self.drive_register(output);
+        }
+        self.fill_input_buffers();
+        self.signal_end_of_stream();
+        self.load_config(self.time.tick());
+
+        let Some((rdy_idx, vc, targets)) = self.allocate() else {
+            if self.input_buffers_empty() {
+                // Nothing is buffered, so wait for the next element on any link. A load that came in meanwhile 
+                // found the switch idle.
+                let idle_since = self.time.tick();
+                let rdy_idx = *self.get_first_available_receiver_inputs().split_first().ok_or("All inputs closed.")?.0;
+                self.take_input(rdy_idx);
+                self.load_config(idle_since);
+            } else {
+                // Every buffered element waits for room in an output virtual channel.
+                self.time.incr_cycles(1);
+            }
+            return Ok(());
+        };
+
+        let input = self.rt_data.input_buffers[rdy_idx][vc].pop_front().unwrap();
+        if let Some(credit_return) = &self.rt_data.credit_returns[rdy_idx] {
+            credit_return.enqueue(&self.time, ChannelElement::new(self.time.tick(), Credit { vc: vc })).unwrap();
+        }
+        let transfer_cycles = self.transfer_cycles(&input.data);
+        if targets.is_empty() {
+            self.report_unrouted(rdy_idx, vc);
+            self.time.incr_cycles(transfer_cycles as u64);
+            return Ok(());
+        }
         
-        let targets = self.rt_config.routing_table.get(&rdy_idx).expect("Received data from unrouted input!");
-        for o_idx in targets {
-            let target = &self.rt_data.senders[*o_idx];
-            target.enqueue(&self.time, 
-                ChannelElement::new(
-                    self.time.tick() + (self.hw_config.delay)(rdy_idx, *o_idx) as u64,
-                    data.clone())).unwrap();
+        if output_registers {
+            // Writing the registers of all targets takes a cycle. They drive their links from the next cycle on, 
+            // while the switch dequeues the next element.
+            for (o_idx, out_vc) in targets.iter() {
+                let data = PCUData { vc: *out_vc, ..input.data.clone() };
+                self.rt_data.registers[*o_idx] = Some((rdy_idx, ChannelElement::new(self.time.tick() + 1, data)));
+                self.drive_register(*o_idx);
+            }
+            self.time.incr_cycles(1);
+            return Ok(());
+        }
+
+        // The message arrives once its last flit went through.
+        for (o_idx, out_vc) in targets.iter() {
+            let arrival = self.time.tick() + (self.hw_config.delay.delay(rdy_idx, *o_idx) + transfer_cycles - 1) as u64;
+            let data = PCUData { vc: *out_vc, ..input.data.clone() };
+            self.rt_data.output_buffers[*o_idx][*out_vc].push_back(ChannelElement::new(arrival, data));
+            self.send_buffered(*o_idx);
             if !multi_enqueue {
-                self.time.incr_cycles(1);
+                self.time.incr_cycles(transfer_cycles as u64);
             }
         }
 
         if multi_enqueue {
-            self.time.incr_cycles(1);
+            self.time.incr_cycles(transfer_cycles as u64);
         }
         Ok(())
     }
 
-    fn single_dequeue_single_enqueue_iter(&self) -> Result<(), &str> {
-        self.single_deque(false)
+    // No route covers an element: the switch drops it, or stops if that is an error.
+    fn report_unrouted(&self, input: usize, vc: usize) {
+        self.rt_data.monitor.report_unrouted(self.rt_config.unrouted, &self.rt_data.name, input, vc);
+    }
+
+    // Once all inputs closed, the elements left in the output buffers and registers still go out.
+    fn drain_output_buffers(&mut self) {
+        for output in 0..self.hw_config.num_outputs {
+            self.send_buffered(output);
+            self.drive_register(output);
+            while self.rt_data.output_buffers[output].iter().any(|buffer| !buffer.is_empty()) || self.rt_data.registers[output].is_some() {
+                self.wait_for_credit(output);
+            }
+        }
+    }
+
+    fn single_dequeue_single_enqueue_iter(&mut self) -> Result<(), &'static str> {
+        self.single_deque(false, false)
     }
 
-    fn single_dequeue_multi_enqueue_iter(&self) -> Result<(), &str> {
-        self.single_deque(true)
+    fn single_dequeue_multi_enqueue_iter(&mut self) -> Result<(), &'static str> {
+        self.single_deque(true, false)
     }
 
-    fn multi_dequeue_multi_enqueue_iter(&self) -> Result<(), &str> {
+    fn single_dequeue_pipelined_enqueue_iter(&mut self) -> Result<(), &'static str> {
+        self.single_deque(true, true)
+    }
+
+    fn multi_dequeue_multi_enqueue_iter(&self) -> Result<(), &'static str> {
         todo!(); // What, if two inputs route to the same output? Then we must serialize between them.
     }
 }
@@ -153,6 +711,11 @@ impl Context for Switch {
     fn init(&mut self) {}
 
     fn run(&mut self) {
+        for port in self.rt_data.control_ports.iter().filter(|p| p.is_input()) {
+            if !port.wait(&self.time) {
+                return;
+            }
+        }
         loop {
             // Okay, so we'll have to discuss how exactly we're going to structure our interconnect. 
             // There are several possibilities...
@@ -160,9 +723,13 @@ impl Context for Switch {
             match self.hw_config.mode {
                 SwitchMode::SingleEnqueueSingleDequeue => res = self.single_dequeue_single_enqueue_iter(),
                 SwitchMode::MultiEnqueueSingleDequeue => res = self.single_dequeue_multi_enqueue_iter(),
-                SwitchMode::MultiEnqueueMultiDequeue => res = self.multi_dequeue_multi_enqueue_iter()
+                SwitchMode::MultiEnqueueMultiDequeue => res = self.multi_dequeue_multi_enqueue_iter(),
+                SwitchMode::PipelinedBroadcast => res = self.single_dequeue_pipelined_enqueue_iter()
             }
             if let Err(_) = res {
+                self.drain_output_buffers();
+                let done = std::cmp::max(self.rt_data.last_sent.clone(), self.time.tick());
+                self.rt_data.control_ports.iter().filter(|p| !p.is_input()).for_each(|p| p.signal(&self.time, done.clone()));
                 return;
             }
         }
@@ -173,11 +740,11 @@ impl Context for Switch {
 mod tests {
     use std::collections::HashMap;
 
-    use dam::{simulation::{InitializationOptionsBuilder, ProgramBuilder, RunOptions}, utility_contexts::{CheckerContext, GeneratorContext}};
+    use dam::{simulation::{InitializationOptionsBuilder, ProgramBuilder, RunOptions}, structures::Time, types::DAMType, utility_contexts::{CheckerContext, GeneratorContext}};
 
-    use crate::{pcu::PCUData, scalar::Scalar, switch::{Switch, SwitchMode}};
+    use crate::{control::{ConfigLoad, ControlPort, ControlToken}, pcu::PCUData, scalar::Scalar, switch::{Switch, SwitchMode}, test_utils::{Controller, Responder, TimedChecker, TimedGenerator}};
 
-    use super::{HwConfig, RtConfig};
+    use super::{Credit, CreditLink, DelayModel, HwConfig, RtConfig, SwitchError, SwitchErrorKind, SwitchMonitor, UnroutedPolicy};
 
     #[test]
     fn test_passthrough() {
@@ -190,8 +757,6 @@ mod tests {
 
         let map: HashMap<_, _> = [(0, vec![0])].into_iter().collect();
 
-        fn switch_delay(_: usize, _: usize) -> usize { SWITCH_DELAY }
-
         let switch = Switch::new(
             HwConfig {
                 simd: 1,
@@ -199,28 +764,89 @@ mod tests {
                 num_inputs: 1,
                 num_outputs: 1,
                 mode: SwitchMode::SingleEnqueueSingleDequeue,
-                delay: switch_delay,
+                delay: DelayModel::Constant(SWITCH_DELAY),
+                input_buffer_depth: CHAN_SIZE,
+                output_buffer_depth: 1,
+                num_vcs: 1,
             }, 
             RtConfig {
                 routing_table: map,
+                ..Default::default()
             },
             vec![input],
             vec![output]
         );
         
         let gen = GeneratorContext::new(
-            || {0..10}.map(|x| PCUData {data: vec![Scalar::I32(x)]}), snd);
+            || {0..10}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd);
         let rcv = CheckerContext::new(
-             || {0..10}.map(|x| PCUData {data: vec![Scalar::I32(x)]}), rcv);
+             || {0..10}.map(|x| PCUData::new(vec![Scalar::I32(x)])), rcv);
+
+        parent.add_child(gen);
+        parent.add_child(rcv);
+        parent.add_child(switch);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn test_control_ports() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+        const SWITCH_DELAY: usize = 1;
+        const ENABLE: u64 = 20;
+
+        let (snd, input) = parent.bounded(CHAN_SIZE);
+        let (output, rcv) = parent.bounded(CHAN_SIZE);
+        let (enable_snd, enable) = parent.bounded(CHAN_SIZE);
+        let (done, done_rcv) = parent.bounded(CHAN_SIZE);
+
+        let switch = Switch::new(
+            HwConfig {
+                simd: 1,
+                datatype_width: Scalar::I32(0).width(),
+                num_inputs: 1,
+                num_outputs: 1,
+                mode: SwitchMode::SingleEnqueueSingleDequeue,
+                delay: DelayModel::Constant(SWITCH_DELAY),
+                input_buffer_depth: CHAN_SIZE,
+                output_buffer_depth: 1,
+                num_vcs: 1,
+            }, 
+            RtConfig {
+                routing_table: [(0, vec![0])].into_iter().collect(),
+                ..Default::default()
+            },
+            vec![input],
+            vec![output]
+        ).with_control_ports(vec![ControlPort::Input(enable), ControlPort::Output(done)]);
+
+        // The data waits in the input buffer until the switch is enabled.
+        let gen = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd);
+        let enable_gen = TimedGenerator::new(vec![(ENABLE, ControlToken)], enable_snd);
+        let rcv = TimedChecker::new((0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(x)])).collect(), rcv);
+        let done_rcv = TimedChecker::new(vec![ControlToken], done_rcv);
+        let (arrivals, done_arrival) = (rcv.arrivals(), done_rcv.arrivals());
 
         parent.add_child(gen);
+        parent.add_child(enable_gen);
         parent.add_child(rcv);
+        parent.add_child(done_rcv);
         parent.add_child(switch);
         let executed = parent
             .initialize(InitializationOptionsBuilder::default().build().unwrap())
             .unwrap()
             .run(RunOptions::default());
         assert!(executed.passed());
+
+        // The done token goes out with the last element.
+        let last = ENABLE + NUM_ELEMENTS as u64 - 1 + SWITCH_DELAY as u64;
+        assert_eq!(arrivals.lock().unwrap()[0], Time::new(ENABLE + SWITCH_DELAY as u64));
+        assert_eq!(*done_arrival.lock().unwrap(), vec![Time::new(last)]);
     }
 
     #[test]
@@ -236,8 +862,6 @@ mod tests {
 
         let table: HashMap<_, _> = [(0,vec![1])].into_iter().collect(); // TODO: Should this thing be able to route multiple things at once? 
         
-        fn delay_fn(_: usize, _: usize) -> usize { 1 }
-
         let switch = Switch::new(
             HwConfig {
                 simd: 1,
@@ -245,21 +869,25 @@ mod tests {
                 num_inputs: 2,
                 num_outputs: 2,
                 mode: SwitchMode::SingleEnqueueSingleDequeue,
-                delay: delay_fn,
+                delay: DelayModel::Constant(1),
+                input_buffer_depth: CHAN_SIZE,
+                output_buffer_depth: 1,
+                num_vcs: 1,
             }, 
             RtConfig {
                 routing_table: table,
+                ..Default::default()
             },
             vec![inputs_rcv0, inputs_rcv1],
             vec![outputs_snd0, outputs_snd1]
         );
         
         let gen0 = GeneratorContext::new(
-            || {0..10}.map(|x| PCUData {data: vec![Scalar::I32(x)]}), inputs_snd0);
+            || {0..10}.map(|x| PCUData::new(vec![Scalar::I32(x)])), inputs_snd0);
         let gen1 = GeneratorContext::new(|| std::iter::empty(), inputs_snd1);
         let rcv0 = CheckerContext::new(|| std::iter::empty(), outputs_rcv0);
         let rcv1 = CheckerContext::new(
-            || {0..10}.map(|x| PCUData {data: vec![Scalar::I32(x)]}), outputs_rcv1);
+            || {0..10}.map(|x| PCUData::new(vec![Scalar::I32(x)])), outputs_rcv1);
 
         parent.add_child(gen0);
         parent.add_child(gen1);
@@ -284,19 +912,21 @@ mod tests {
         let (output0, rcv0) = parent.bounded(CHAN_SIZE);
         let (output1, rcv1) = parent.bounded(CHAN_SIZE);
 
-        fn delay_fn(_: usize, _: usize) -> usize { SWITCH_DELAY }
-
         let hwConfig = HwConfig {
             simd: 1,
             datatype_width: Scalar::I32(0).width(),
             num_inputs: 1,
             num_outputs: 2,
             mode: SwitchMode::SingleEnqueueSingleDequeue,
-            delay: delay_fn,
+            delay: DelayModel::Constant(SWITCH_DELAY),
+            input_buffer_depth: CHAN_SIZE,
+            output_buffer_depth: 1,
+            num_vcs: 1,
         };
 
         let rtConfig = RtConfig {
             routing_table: [(0,vec![0,1])].into_iter().collect(),
+            ..Default::default()
         };
 
         let switch = Switch::new(
@@ -306,14 +936,12 @@ mod tests {
         );
         
         let gen = GeneratorContext::new( 
-            // TODO: There is something weird happening here: After _some_ runs, the Generator just does not generate. 
-            // Seemingly, it never is scheduled or something? peek() always just returns time(0).
-            || {0..NUM_ELEMENTS}.map(|x| PCUData {data: vec![Scalar::I32(x)]}), snd);
+            || {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd);
 
         let rcv0 = CheckerContext::new(
-            || {0..NUM_ELEMENTS}.map(|x| PCUData {data: vec![Scalar::I32(x)]}), rcv0);
+            || {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), rcv0);
         let rcv1 = CheckerContext::new(
-            || {0..NUM_ELEMENTS}.map(|x| PCUData {data: vec![Scalar::I32(x)]}), rcv1);
+            || {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), rcv1);
 
         parent.add_child(gen);
         parent.add_child(rcv0);
@@ -327,4 +955,848 @@ mod tests {
         assert_eq!(executed.elapsed_cycles().unwrap(), NUM_ELEMENTS as u64 * 2 + SWITCH_DELAY as u64);
         assert!(executed.passed());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_pipelined_broadcast() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+        const SWITCH_DELAY: usize = 2;
+
+        let (snd, input) = parent.bounded(CHAN_SIZE);
+        let (output0, rcv0) = parent.bounded(CHAN_SIZE);
+        let (output1, rcv1) = parent.bounded(CHAN_SIZE);
+
+        let hw_config = HwConfig {
+            simd: 1,
+            datatype_width: Scalar::I32(0).width(),
+            num_inputs: 1,
+            num_outputs: 2,
+            mode: SwitchMode::PipelinedBroadcast,
+            delay: DelayModel::Constant(SWITCH_DELAY),
+            input_buffer_depth: CHAN_SIZE,
+            output_buffer_depth: 1,
+            num_vcs: 1,
+        };
+
+        let rt_config = RtConfig {
+            routing_table: [(0,vec![0,1])].into_iter().collect(),
+            ..Default::default()
+        };
+
+        let switch = Switch::new(
+            hw_config, rt_config, 
+            vec![input],
+            vec![output0, output1]
+        );
+        
+        let gen = GeneratorContext::new( 
+            || {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd);
+
+        let rcv0 = CheckerContext::new(
+            || {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), rcv0);
+        let rcv1 = CheckerContext::new(
+            || {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), rcv1);
+
+        parent.add_child(gen);
+        parent.add_child(rcv0);
+        parent.add_child(rcv1);
+        parent.add_child(switch);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        executed.dump_failures();
+        // One element per cycle, plus a cycle in the output registers.
+        assert_eq!(executed.elapsed_cycles().unwrap(), NUM_ELEMENTS as u64 + SWITCH_DELAY as u64 + 1);
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn test_pipelined_serialization() {
+        const NUM_ELEMENTS: u64 = 10;
+
+        // Broadcasts two-lane vectors over one-lane links, so that every element takes two cycles on a link.
+        let run = |mode: SwitchMode| {
+            let mut parent = ProgramBuilder::default();
+            let (snd, input) = parent.bounded(8);
+            let (output0, rcv0) = parent.bounded(8);
+            let (output1, rcv1) = parent.bounded(8);
+            let switch = Switch::new(
+                HwConfig {
+                    simd: 1,
+                    datatype_width: Scalar::I32(0).width(),
+                    num_inputs: 1,
+                    num_outputs: 2,
+                    mode: mode,
+                    delay: DelayModel::Constant(1),
+                    input_buffer_depth: 8,
+                    output_buffer_depth: 1,
+                    num_vcs: 1,
+                },
+                RtConfig { routing_table: [(0, vec![0, 1])].into_iter().collect(), ..Default::default() },
+                vec![input],
+                vec![output0, output1]
+            );
+
+            let element = |x: u64| PCUData::new(vec![Scalar::I32(x as i32); 2]);
+            let gen = TimedGenerator::new((0..NUM_ELEMENTS).map(|x| (x, element(x))).collect(), snd);
+            let rcv0 = TimedChecker::new((0..NUM_ELEMENTS).map(element).collect(), rcv0);
+            let rcv1 = TimedChecker::new((0..NUM_ELEMENTS).map(element).collect(), rcv1);
+            let (arrivals0, arrivals1) = (rcv0.arrivals(), rcv1.arrivals());
+
+            parent.add_child(gen);
+            parent.add_child(switch);
+            parent.add_child(rcv0);
+            parent.add_child(rcv1);
+            let executed = parent
+                .initialize(InitializationOptionsBuilder::default().build().unwrap())
+                .unwrap()
+                .run(RunOptions::default());
+            assert!(executed.passed());
+            let arrivals0 = arrivals0.lock().unwrap().clone();
+            let arrivals1 = arrivals1.lock().unwrap().clone();
+            (arrivals0, arrivals1)
+        };
+        let every = |cycles: u64, first: u64| (0..NUM_ELEMENTS).map(|x| Time::new(first + cycles * x)).collect::<Vec<_>>();
+
+        // One output after the other: an element takes two cycles per output.
+        assert_eq!(run(SwitchMode::SingleEnqueueSingleDequeue), (every(4, 2), every(4, 4)));
+        // The registers send to both outputs at once, and take the next element as the last flit leaves.
+        assert_eq!(run(SwitchMode::PipelinedBroadcast), (every(2, 3), every(2, 3)));
+    }
+
+    #[test]
+    fn test_reconfiguration() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+        const LATENCY: usize = 20;
+
+        let (snd, input) = parent.bounded(CHAN_SIZE);
+        let (output0, rcv0) = parent.bounded(CHAN_SIZE);
+        let (output1, rcv1) = parent.bounded(CHAN_SIZE);
+        let (config_snd, config_rcv) = parent.bounded(1);
+
+        let switch = Switch::new(
+            HwConfig {
+                simd: 1,
+                datatype_width: Scalar::I32(0).width(),
+                num_inputs: 1,
+                num_outputs: 2,
+                mode: SwitchMode::SingleEnqueueSingleDequeue,
+                delay: DelayModel::Constant(1),
+                input_buffer_depth: CHAN_SIZE,
+                output_buffer_depth: 1,
+                num_vcs: 1,
+            },
+            RtConfig { routing_table: [(0, vec![0])].into_iter().collect(), ..Default::default() },
+            vec![input],
+            vec![output0, output1]
+        ).with_config_port(config_rcv);
+
+        // The load arrives with the first element, so everything goes to output 1 once the switch reconfigured.
+        let load = ConfigLoad {
+            config: RtConfig { routing_table: [(0, vec![1])].into_iter().collect(), ..Default::default() },
+            latency: LATENCY
+        };
+        let elements = || (0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(x)]));
+        parent.add_child(Controller::new(vec![(Some(load), elements().collect())], config_snd, snd));
+        parent.add_child(switch);
+        parent.add_child(CheckerContext::new(|| std::iter::empty(), rcv0));
+        parent.add_child(CheckerContext::new(elements, rcv1));
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+        assert_eq!(executed.elapsed_cycles().unwrap(), (LATENCY + NUM_ELEMENTS as usize + 1) as u64);
+    }
+
+    #[test]
+    fn test_reconfiguration_while_busy() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const LATENCY: u64 = 20;
+        const LANES: usize = 4;
+
+        let (snd, input) = parent.bounded(CHAN_SIZE);
+        let (output0, rcv0) = parent.bounded(CHAN_SIZE);
+        let (output1, rcv1) = parent.bounded(CHAN_SIZE);
+        let (config_snd, config_rcv) = parent.bounded(1);
+
+        // Every vector takes 4 cycles on a link.
+        let switch = Switch::new(
+            HwConfig {
+                simd: 1,
+                datatype_width: Scalar::I32(0).width(),
+                num_inputs: 1,
+                num_outputs: 2,
+                mode: SwitchMode::SingleEnqueueSingleDequeue,
+                delay: DelayModel::Constant(1),
+                input_buffer_depth: CHAN_SIZE,
+                output_buffer_depth: 1,
+                num_vcs: 1,
+            },
+            RtConfig { routing_table: [(0, vec![0])].into_iter().collect(), ..Default::default() },
+            vec![input],
+            vec![output0, output1]
+        ).with_config_port(config_rcv);
+
+        // The load arrives at cycle 1, while the first vector still goes out until cycle 4. The reconfiguration 
+        // only starts after that.
+        let load = ConfigLoad {
+            config: RtConfig { routing_table: [(0, vec![1])].into_iter().collect(), ..Default::default() },
+            latency: LATENCY as usize
+        };
+        let vector = |x: i32| PCUData::new(vec![Scalar::I32(x); LANES]);
+        let controller = Controller::new(vec![(None, vec![vector(0)]), (Some(load), vec![vector(1)])], config_snd, snd);
+        let rcv0 = TimedChecker::new(vec![vector(0)], rcv0);
+        let rcv1 = TimedChecker::new(vec![vector(1)], rcv1);
+        let (arrivals0, arrivals1) = (rcv0.arrivals(), rcv1.arrivals());
+
+        parent.add_child(controller);
+        parent.add_child(switch);
+        parent.add_child(rcv0);
+        parent.add_child(rcv1);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+        assert_eq!(*arrivals0.lock().unwrap(), vec![Time::new(LANES as u64)]);
+        assert_eq!(*arrivals1.lock().unwrap(), vec![Time::new(LANES as u64 + LATENCY + LANES as u64)]);
+    }
+
+    #[test]
+    fn test_link_bandwidth() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+        const SWITCH_DELAY: usize = 1;
+        const LANES: usize = 4;
+
+        let (snd, input) = parent.bounded(CHAN_SIZE);
+        let (output, rcv) = parent.bounded(CHAN_SIZE);
+
+        // The link carries a single I32 per cycle, so every 4-lane vector takes 4 cycles.
+        let switch = Switch::new(
+            HwConfig {
+                simd: 1,
+                datatype_width: Scalar::I32(0).width(),
+                num_inputs: 1,
+                num_outputs: 1,
+                mode: SwitchMode::SingleEnqueueSingleDequeue,
+                delay: DelayModel::Constant(SWITCH_DELAY),
+                input_buffer_depth: CHAN_SIZE,
+                output_buffer_depth: 1,
+                num_vcs: 1,
+            },
+            RtConfig {
+                routing_table: [(0, vec![0])].into_iter().collect(),
+                ..Default::default()
+            },
+            vec![input],
+            vec![output]
+        );
+
+        let vector = |x: i32| PCUData::new(vec![Scalar::I32(x); LANES]);
+        assert_eq!(vector(0).dam_size(), LANES * 32);
+        assert_eq!(switch.transfer_cycles(&vector(0)), LANES);
+
+        let gen = GeneratorContext::new(move || {0..NUM_ELEMENTS}.map(vector), snd);
+        let rcv = CheckerContext::new(move || {0..NUM_ELEMENTS}.map(vector), rcv);
+
+        parent.add_child(gen);
+        parent.add_child(rcv);
+        parent.add_child(switch);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+        assert_eq!(executed.elapsed_cycles().unwrap(), NUM_ELEMENTS as u64 * LANES as u64 + SWITCH_DELAY as u64);
+    }
+
+    #[test]
+    fn test_delay_models() {
+        let table = DelayModel::from_json(r#"{"Table": [[1, 2], [3, 4]]}"#).unwrap();
+        assert_eq!(table.delay(1, 0), 3);
+
+        let distance = DelayModel::from_json(
+            r#"{"Distance": {"input_positions": [[0, 0]], "output_positions": [[2, 1], [0, -1]], "base": 1, "per_unit": 2}}"#).unwrap();
+        assert_eq!(distance.delay(0, 0), 7);
+        assert_eq!(distance.delay(0, 1), 3);
+
+        let hops = 3;
+        let closure = DelayModel::Closure(Box::new(move |input, output| hops + input + output));
+        assert_eq!(closure.delay(1, 2), 6);
+    }
+
+    #[test]
+    fn test_credit_flow_control() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const BUFFER_DEPTH: usize = 2;
+        const SWITCH_DELAY: usize = 1;
+
+        let hw_config = || HwConfig {
+            simd: 1,
+            datatype_width: Scalar::I32(0).width(),
+            num_inputs: 1,
+            num_outputs: 1,
+            mode: SwitchMode::SingleEnqueueSingleDequeue,
+            delay: DelayModel::Constant(SWITCH_DELAY),
+            input_buffer_depth: BUFFER_DEPTH,
+            output_buffer_depth: 1,
+            num_vcs: 1,
+        };
+
+        let (snd, input) = parent.bounded(CHAN_SIZE);
+        let (output, rcv) = parent.bounded(CHAN_SIZE);
+        let (credit_snd, credit_rcv) = parent.bounded(CHAN_SIZE);
+
+        // The consumer has room for two elements and only frees a slot at cycles 30 and 40.
+        let switch = Switch::new(hw_config(), RtConfig { routing_table: [(0, vec![0])].into_iter().collect(), ..Default::default() }, 
+            vec![input], vec![output])
+            .with_credit_ports(vec![None], vec![Some(CreditLink::new(credit_rcv, &hw_config()))]);
+        let gen = GeneratorContext::new(|| {0..4}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd);
+        let credits = TimedGenerator::new(vec![(30, Credit { vc: 0 }), (40, Credit { vc: 0 })], credit_snd);
+        let rcv = TimedChecker::new((0..4).map(|x| PCUData::new(vec![Scalar::I32(x)])).collect(), rcv);
+        let arrivals = rcv.arrivals();
+
+        parent.add_child(gen);
+        parent.add_child(credits);
+        parent.add_child(rcv);
+        parent.add_child(switch);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+
+        // The first two elements use the initial credits, the others wait in the output buffer for a credit.
+        let delay = SWITCH_DELAY as u64;
+        assert_eq!(*arrivals.lock().unwrap(), vec![Time::new(delay), Time::new(1 + delay), Time::new(30), Time::new(40)]);
+    }
+
+    #[test]
+    fn test_credit_chain() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 20;
+        const BUFFER_DEPTH: usize = 2;
+
+        let hw_config = || HwConfig {
+            simd: 1,
+            datatype_width: Scalar::I32(0).width(),
+            num_inputs: 1,
+            num_outputs: 1,
+            mode: SwitchMode::SingleEnqueueSingleDequeue,
+            delay: DelayModel::Constant(1),
+            input_buffer_depth: BUFFER_DEPTH,
+            output_buffer_depth: 1,
+            num_vcs: 1,
+        };
+        let rt_config = || RtConfig { routing_table: [(0, vec![0])].into_iter().collect(), ..Default::default() };
+
+        // gen -> first -> second -> rcv, where second returns credits to first.
+        let (snd, first_in) = parent.bounded(CHAN_SIZE);
+        let (first_out, second_in) = parent.bounded(CHAN_SIZE);
+        let (second_out, rcv) = parent.bounded(CHAN_SIZE);
+        let (credit_snd, credit_rcv) = parent.bounded(CHAN_SIZE);
+
+        let first = Switch::new(hw_config(), rt_config(), vec![first_in], vec![first_out])
+            .with_credit_ports(vec![None], vec![Some(CreditLink::new(credit_rcv, &hw_config()))]);
+        let second = Switch::new(hw_config(), rt_config(), vec![second_in], vec![second_out])
+            .with_credit_ports(vec![Some(credit_snd)], vec![None]);
+
+        let gen = GeneratorContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), snd);
+        let rcv = CheckerContext::new(|| {0..NUM_ELEMENTS}.map(|x| PCUData::new(vec![Scalar::I32(x)])), rcv);
+
+        parent.add_child(gen);
+        parent.add_child(first);
+        parent.add_child(second);
+        parent.add_child(rcv);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn test_sparse_input() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+        const SLOW_DELAY: usize = 100;
+
+        let hw_config = |num_ports: usize, delay: usize| HwConfig {
+            simd: 1,
+            datatype_width: Scalar::I32(0).width(),
+            num_inputs: num_ports,
+            num_outputs: num_ports,
+            mode: SwitchMode::SingleEnqueueSingleDequeue,
+            delay: DelayModel::Constant(delay),
+            input_buffer_depth: CHAN_SIZE,
+            output_buffer_depth: 1,
+            num_vcs: 1,
+        };
+        let straight = |num_ports: usize| RtConfig { routing_table: (0..num_ports).map(|i| (i, vec![i])).collect(), ..Default::default() };
+
+        // Input 1 of the switch only sees data once it went through the slow switch, long after input 0 is done.
+        let (snd0, in0) = parent.bounded(CHAN_SIZE);
+        let (snd1, slow_in) = parent.bounded(CHAN_SIZE);
+        let (slow_out, in1) = parent.bounded(CHAN_SIZE);
+        let (out0, rcv0) = parent.bounded(CHAN_SIZE);
+        let (out1, rcv1) = parent.bounded(CHAN_SIZE);
+        let slow = Switch::new(hw_config(1, SLOW_DELAY), straight(1), vec![slow_in], vec![slow_out]);
+        let switch = Switch::new(hw_config(2, 1), straight(2), vec![in0, in1], vec![out0, out1]);
+
+        let elements = || (0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(x)]));
+        let sent = || elements().enumerate().map(|(i, x)| (i as u64, x)).collect::<Vec<_>>();
+        let rcv0 = TimedChecker::new(elements().collect(), rcv0);
+        let rcv1 = TimedChecker::new(elements().collect(), rcv1);
+        let (arrivals0, arrivals1) = (rcv0.arrivals(), rcv1.arrivals());
+        parent.add_child(TimedGenerator::new(sent(), snd0));
+        parent.add_child(TimedGenerator::new(sent(), snd1));
+        parent.add_child(slow);
+        parent.add_child(switch);
+        parent.add_child(rcv0);
+        parent.add_child(rcv1);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+
+        // Input 0 goes through while input 1 idles, input 1 one cycle after the slow switch delivered.
+        let arrivals = |first: u64| (0..NUM_ELEMENTS as u64).map(|x| Time::new(first + x)).collect::<Vec<_>>();
+        assert_eq!(*arrivals0.lock().unwrap(), arrivals(1));
+        assert_eq!(*arrivals1.lock().unwrap(), arrivals(SLOW_DELAY as u64 + 1));
+    }
+
+    #[test]
+    fn test_idle_input() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+
+        let hw_config = |num_inputs: usize, num_outputs: usize| HwConfig {
+            simd: 1,
+            datatype_width: Scalar::I32(0).width(),
+            num_inputs: num_inputs,
+            num_outputs: num_outputs,
+            mode: SwitchMode::SingleEnqueueSingleDequeue,
+            delay: DelayModel::Constant(1),
+            input_buffer_depth: CHAN_SIZE,
+            output_buffer_depth: 1,
+            num_vcs: 1,
+        };
+
+        // The upstream switch never sends anything on the link to input 1 of the switch, but keeps it open 
+        // until its own input closes.
+        let (snd0, in0) = parent.bounded(CHAN_SIZE);
+        let (snd1, upstream_in) = parent.bounded(CHAN_SIZE);
+        let (upstream_out0, upstream_rcv) = parent.bounded(CHAN_SIZE);
+        let (upstream_out1, in1) = parent.bounded(CHAN_SIZE);
+        let (out, rcv) = parent.bounded(CHAN_SIZE);
+        let upstream = Switch::new(hw_config(1, 2),
+            RtConfig { routing_table: [(0, vec![0])].into_iter().collect(), ..Default::default() },
+            vec![upstream_in], vec![upstream_out0, upstream_out1]);
+        let switch = Switch::new(hw_config(2, 1),
+            RtConfig { routing_table: [(0, vec![0]), (1, vec![0])].into_iter().collect(), ..Default::default() },
+            vec![in0, in1], vec![out]);
+
+        let elements = || (0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(x)]));
+        parent.add_child(GeneratorContext::new(elements, snd0));
+        parent.add_child(GeneratorContext::new(elements, snd1));
+        parent.add_child(upstream);
+        parent.add_child(switch);
+        parent.add_child(CheckerContext::new(elements, upstream_rcv));
+        parent.add_child(CheckerContext::new(elements, rcv));
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn test_lagging_input() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+
+        // Input 0 goes to the responder, which answers on input 1 once it saw all of input 0. Until then, the 
+        // responder's time lags behind the switch, and the switch has to keep forwarding input 0 while it waits 
+        // for the responder to catch up. Waiting for data on input 1 instead would deadlock.
+        let (snd, in0) = parent.bounded(CHAN_SIZE);
+        let (out0, responder_in) = parent.bounded(CHAN_SIZE);
+        let (responder_out, in1) = parent.bounded(CHAN_SIZE);
+        let (out1, rcv) = parent.bounded(CHAN_SIZE);
+        let switch = Switch::new(
+            HwConfig {
+                simd: 1,
+                datatype_width: Scalar::I32(0).width(),
+                num_inputs: 2,
+                num_outputs: 2,
+                mode: SwitchMode::SingleEnqueueSingleDequeue,
+                delay: DelayModel::Constant(1),
+                input_buffer_depth: CHAN_SIZE,
+                output_buffer_depth: 1,
+                num_vcs: 1,
+            },
+            RtConfig { routing_table: [(0, vec![0]), (1, vec![1])].into_iter().collect(), ..Default::default() },
+            vec![in0, in1],
+            vec![out0, out1]
+        );
+
+        let reply = PCUData::new(vec![Scalar::I32(NUM_ELEMENTS)]);
+        let gen = TimedGenerator::new((0..NUM_ELEMENTS).map(|x| (x as u64, PCUData::new(vec![Scalar::I32(x)]))).collect(), snd);
+        let responder = Responder::new(NUM_ELEMENTS as usize, reply.clone(), responder_in, responder_out);
+        let rcv = TimedChecker::new(vec![reply], rcv);
+        let arrivals = rcv.arrivals();
+
+        parent.add_child(gen);
+        parent.add_child(switch);
+        parent.add_child(responder);
+        parent.add_child(rcv);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+
+        // The last element reaches the responder at cycle NUM_ELEMENTS, its reply the switch one cycle later.
+        assert_eq!(*arrivals.lock().unwrap(), vec![Time::new(NUM_ELEMENTS as u64 + 2)]);
+    }
+
+    #[test]
+    fn test_virtual_channels() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+
+        let (snd_a, input_a) = parent.bounded(CHAN_SIZE);
+        let (snd_b, input_b) = parent.bounded(CHAN_SIZE);
+        let (out_a, rcv_a) = parent.bounded(CHAN_SIZE);
+        let (out_b, rcv_b) = parent.bounded(CHAN_SIZE);
+
+        // VC 0 of input 0 goes to VC 1 of output 0, VC 1 of input 1 to VC 0 of output 1.
+        let vc_routes: HashMap<_, _> = [((0, 0), vec![(0, 1)]), ((1, 1), vec![(1, 0)])].into_iter().collect();
+        let switch = Switch::new(
+            HwConfig {
+                simd: 1,
+                datatype_width: Scalar::I32(0).width(),
+                num_inputs: 2,
+                num_outputs: 2,
+                mode: SwitchMode::SingleEnqueueSingleDequeue,
+                delay: DelayModel::Constant(1),
+                input_buffer_depth: 2,
+                output_buffer_depth: 2,
+                num_vcs: 2,
+            },
+            RtConfig { vc_routes: vc_routes, ..Default::default() },
+            vec![input_a, input_b],
+            vec![out_a, out_b]
+        );
+
+        let element = |x: i32, vc: usize| PCUData::new(vec![Scalar::I32(x)]).with_vc(vc);
+        let gen_a = GeneratorContext::new(move || (0..NUM_ELEMENTS).step_by(2).map(move |x| element(x, 0)), snd_a);
+        let gen_b = GeneratorContext::new(move || (1..NUM_ELEMENTS).step_by(2).map(move |x| element(x, 1)), snd_b);
+        let rcv_a = CheckerContext::new(move || (0..NUM_ELEMENTS).step_by(2).map(move |x| element(x, 1)), rcv_a);
+        let rcv_b = CheckerContext::new(move || (1..NUM_ELEMENTS).step_by(2).map(move |x| element(x, 0)), rcv_b);
+
+        parent.add_child(gen_a);
+        parent.add_child(gen_b);
+        parent.add_child(switch);
+        parent.add_child(rcv_a);
+        parent.add_child(rcv_b);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+    }
+
+    #[test]
+    fn test_blocked_virtual_channel() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 6;
+        const BUFFER_DEPTH: usize = 2;
+        const RELEASE: u64 = 50;
+
+        let hw_config = |num_inputs: usize, num_outputs: usize| HwConfig {
+            simd: 1,
+            datatype_width: Scalar::I32(0).width(),
+            num_inputs: num_inputs,
+            num_outputs: num_outputs,
+            mode: SwitchMode::SingleEnqueueSingleDequeue,
+            delay: DelayModel::Constant(1),
+            input_buffer_depth: BUFFER_DEPTH,
+            output_buffer_depth: 1,
+            num_vcs: 2,
+        };
+
+        // gen_a (VC 0) and gen_b (VC 1) share the link from first to second, which returns credits to first.
+        // Second sends VC 0 to rcv_a and VC 1 to rcv_b. rcv_a only has room for two elements before it frees 
+        // a slot at cycle RELEASE and after.
+        let (snd_a, first_a) = parent.bounded(CHAN_SIZE);
+        let (snd_b, first_b) = parent.bounded(CHAN_SIZE);
+        let (first_out, second_in) = parent.bounded(CHAN_SIZE);
+        let (out_a, rcv_a) = parent.bounded(CHAN_SIZE);
+        let (out_b, rcv_b) = parent.bounded(CHAN_SIZE);
+        let (credit_snd, credit_rcv) = parent.bounded(2 * BUFFER_DEPTH);
+        let (blocked_snd, blocked_rcv) = parent.bounded(2 * BUFFER_DEPTH);
+
+        let first = Switch::new(hw_config(2, 1), RtConfig { routing_table: [(0, vec![0]), (1, vec![0])].into_iter().collect(), ..Default::default() },
+            vec![first_a, first_b], vec![first_out])
+            .with_credit_ports(vec![None, None], vec![Some(CreditLink::new(credit_rcv, &hw_config(1, 2)))]);
+        let vc_routes: HashMap<_, _> = [((0, 0), vec![(0, 0)]), ((0, 1), vec![(1, 1)])].into_iter().collect();
+        let second = Switch::new(hw_config(1, 2), RtConfig { vc_routes: vc_routes, ..Default::default() }, 
+            vec![second_in], vec![out_a, out_b])
+            .with_credit_ports(vec![Some(credit_snd)], vec![Some(CreditLink::new(blocked_rcv, &hw_config(1, 2))), None]);
+
+        let element = |x: i32, vc: usize| PCUData::new(vec![Scalar::I32(x)]).with_vc(vc);
+        let gen_a = GeneratorContext::new(move || (0..NUM_ELEMENTS).map(move |x| element(x, 0)), snd_a);
+        let gen_b = GeneratorContext::new(move || (0..NUM_ELEMENTS).map(move |x| element(x, 1)), snd_b);
+        let release = TimedGenerator::new((0..NUM_ELEMENTS as u64 - 2).map(|x| (RELEASE + x, Credit { vc: 0 })).collect(), blocked_snd);
+        let rcv_a = TimedChecker::new((0..NUM_ELEMENTS).map(|x| element(x, 0)).collect(), rcv_a);
+        let rcv_b = TimedChecker::new((0..NUM_ELEMENTS).map(|x| element(x, 1)).collect(), rcv_b);
+        let (arrivals_a, arrivals_b) = (rcv_a.arrivals(), rcv_b.arrivals());
+
+        parent.add_child(gen_a);
+        parent.add_child(gen_b);
+        parent.add_child(release);
+        parent.add_child(first);
+        parent.add_child(second);
+        parent.add_child(rcv_a);
+        parent.add_child(rcv_b);
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+
+        // VC 0 backs up through both switches, but VC 1 goes through before rcv_a frees a slot.
+        let arrivals_a = arrivals_a.lock().unwrap();
+        assert!(arrivals_a[..2].iter().all(|time| *time < Time::new(RELEASE)));
+        assert!(arrivals_a[2..].iter().all(|time| *time >= Time::new(RELEASE)));
+        assert!(arrivals_b.lock().unwrap().iter().all(|time| *time < Time::new(RELEASE)));
+    }
+
+    #[test]
+    fn test_serialization() {
+        let mut parent = ProgramBuilder::default();
+        let (_, input) = parent.bounded::<PCUData>(1);
+        let (output, _) = parent.bounded::<PCUData>(1);
+
+        // Two 32-bit lanes per cycle.
+        let switch = Switch::new(
+            HwConfig {
+                simd: 2,
+                datatype_width: Scalar::I32(0).width(),
+                num_inputs: 1,
+                num_outputs: 1,
+                mode: SwitchMode::SingleEnqueueSingleDequeue,
+                delay: DelayModel::Constant(1),
+                input_buffer_depth: 1,
+                output_buffer_depth: 1,
+                num_vcs: 1,
+            },
+            RtConfig { routing_table: [(0, vec![0])].into_iter().collect(), ..Default::default() },
+            vec![input],
+            vec![output]
+        );
+
+        assert_eq!(switch.transfer_cycles(&PCUData::new(vec![Scalar::I32(0); 2])), 1);
+        assert_eq!(switch.transfer_cycles(&PCUData::new(vec![Scalar::I32(0); 5])), 3);
+        assert_eq!(switch.transfer_cycles(&PCUData::new(vec![Scalar::Bit(true); 64])), 1);
+        assert_eq!(switch.transfer_cycles(&PCUData::new(vec![Scalar::Bit(true); 65])), 2);
+    }
+
+    #[test]
+    fn test_unrouted_input() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+        const NUM_ELEMENTS: i32 = 10;
+
+        let hw_config = || HwConfig {
+            simd: 1,
+            datatype_width: Scalar::I32(0).width(),
+            num_inputs: 2,
+            num_outputs: 2,
+            mode: SwitchMode::SingleEnqueueSingleDequeue,
+            delay: DelayModel::Constant(1),
+            input_buffer_depth: CHAN_SIZE,
+            output_buffer_depth: 1,
+            num_vcs: 1,
+        };
+        // Only input 0 is routed. Input 1 of the first switch goes to its default output, input 1 of the 
+        // second is dropped.
+        let rt_config = |unrouted: UnroutedPolicy| RtConfig { 
+            routing_table: [(0, vec![0])].into_iter().collect(), 
+            unrouted: unrouted, 
+            ..Default::default() 
+        };
+        let monitor = SwitchMonitor::default();
+        let elements = || (0..NUM_ELEMENTS).map(|x| PCUData::new(vec![Scalar::I32(x)]));
+        for (name, unrouted) in [("default", UnroutedPolicy::DefaultOutput(1)), ("drop", UnroutedPolicy::Drop)] {
+            let (snd0, in0) = parent.bounded(CHAN_SIZE);
+            let (snd1, in1) = parent.bounded(CHAN_SIZE);
+            let (out0, rcv0) = parent.bounded(CHAN_SIZE);
+            let (out1, rcv1) = parent.bounded(CHAN_SIZE);
+            let switch = Switch::new(hw_config(), rt_config(unrouted), vec![in0, in1], vec![out0, out1])
+                .with_name(name)
+                .with_monitor(monitor.clone());
+            parent.add_child(switch);
+            parent.add_child(GeneratorContext::new(elements, snd0));
+            parent.add_child(GeneratorContext::new(|| std::iter::once(PCUData::new(vec![Scalar::I32(-1)])), snd1));
+            parent.add_child(CheckerContext::new(elements, rcv0));
+            if unrouted == UnroutedPolicy::DefaultOutput(1) {
+                parent.add_child(CheckerContext::new(|| std::iter::once(PCUData::new(vec![Scalar::I32(-1)])), rcv1));
+            } else {
+                parent.add_child(CheckerContext::new(|| std::iter::empty(), rcv1));
+            }
+        }
+
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(executed.passed());
+        assert_eq!(monitor.dropped("default"), 0);
+        assert_eq!(monitor.dropped("drop"), 1);
+        assert!(monitor.errors().is_empty());
+    }
+
+    #[test]
+    fn test_unrouted_input_error() {
+        let mut parent = ProgramBuilder::default();
+        const CHAN_SIZE: usize = 8;
+
+        let (snd0, in0) = parent.bounded(CHAN_SIZE);
+        let (snd1, in1) = parent.bounded(CHAN_SIZE);
+        let (out0, rcv0) = parent.bounded(CHAN_SIZE);
+
+        // Input 1 is not routed, and the default policy treats data on it as an error.
+        let monitor = SwitchMonitor::default();
+        let switch = Switch::new(
+            HwConfig {
+                simd: 1,
+                datatype_width: Scalar::I32(0).width(),
+                num_inputs: 2,
+                num_outputs: 1,
+                mode: SwitchMode::SingleEnqueueSingleDequeue,
+                delay: DelayModel::Constant(1),
+                input_buffer_depth: CHAN_SIZE,
+                output_buffer_depth: 1,
+                num_vcs: 1,
+            },
+            RtConfig { routing_table: [(0, vec![0])].into_iter().collect(), ..Default::default() },
+            vec![in0, in1],
+            vec![out0]
+        ).with_name("error").with_monitor(monitor.clone());
+        parent.add_child(switch);
+        parent.add_child(GeneratorContext::new(|| std::iter::empty(), snd0));
+        parent.add_child(GeneratorContext::new(|| std::iter::once(PCUData::new(vec![Scalar::I32(-1)])), snd1));
+        parent.add_child(CheckerContext::new(|| std::iter::empty(), rcv0));
+
+        // The switch stops on the unrouted element, so the run does not pass.
+        let executed = parent
+            .initialize(InitializationOptionsBuilder::default().build().unwrap())
+            .unwrap()
+            .run(RunOptions::default());
+        assert!(!executed.passed());
+        let error = SwitchError { switch: String::from("error"), port: 1, kind: SwitchErrorKind::UnroutedInput { vc: 0 } };
+        assert_eq!(error.to_string(), "error: data on unrouted input 1 (VC 0)");
+        assert_eq!(monitor.errors(), vec![error]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Routing table routes an input to no output. Leave it out to use the unrouted policy.")]
+    fn test_empty_route() {
+        let mut parent = ProgramBuilder::default();
+        let (_, input) = parent.bounded::<PCUData>(1);
+        let (output, _) = parent.bounded::<PCUData>(1);
+
+        let _ = Switch::new(
+            HwConfig {
+                simd: 1,
+                datatype_width: Scalar::I32(0).width(),
+                num_inputs: 1,
+                num_outputs: 1,
+                mode: SwitchMode::SingleEnqueueSingleDequeue,
+                delay: DelayModel::Constant(1),
+                input_buffer_depth: 1,
+                output_buffer_depth: 1,
+                num_vcs: 1,
+            },
+            RtConfig { routing_table: [(0, vec![])].into_iter().collect(), ..Default::default() },
+            vec![input],
+            vec![output]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Routing table routes to an output that does not exist.")]
+    fn test_invalid_route() {
+        let mut parent = ProgramBuilder::default();
+        let (_, input) = parent.bounded::<PCUData>(1);
+        let (output, _) = parent.bounded::<PCUData>(1);
+
+        let _ = Switch::new(
+            HwConfig {
+                simd: 1,
+                datatype_width: Scalar::I32(0).width(),
+                num_inputs: 1,
+                num_outputs: 1,
+                mode: SwitchMode::SingleEnqueueSingleDequeue,
+                delay: DelayModel::Constant(1),
+                input_buffer_depth: 1,
+                output_buffer_depth: 1,
+                num_vcs: 1,
+            },
+            RtConfig { routing_table: [(0, vec![1])].into_iter().collect(), ..Default::default() },
+            vec![input],
+            vec![output]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Switch lanes have to be as wide as a scalar type.")]
+    fn test_invalid_datatype_width() {
+        HwConfig {
+            simd: 1,
+            datatype_width: 16,
+            num_inputs: 1,
+            num_outputs: 1,
+            mode: SwitchMode::SingleEnqueueSingleDequeue,
+            delay: DelayModel::Constant(1),
+            input_buffer_depth: 1,
+            output_buffer_depth: 1,
+            num_vcs: 1,
+        }.validate();
+    }
+
+    #[test]
+    #[should_panic(expected = "Switch link is wider than the vectors of the producer.")]
+    fn test_link_wider_than_producer() {
+        let hw_config = HwConfig {
+            simd: 4,
+            datatype_width: Scalar::I32(0).width(),
+            num_inputs: 1,
+            num_outputs: 1,
+            mode: SwitchMode::SingleEnqueueSingleDequeue,
+            delay: DelayModel::Constant(1),
+            input_buffer_depth: 1,
+            output_buffer_depth: 1,
+            num_vcs: 1,
+        };
+        hw_config.validate_producer(4, Scalar::I32(0).width());
+        hw_config.validate_producer(2, Scalar::I32(0).width());
+    }
+}
@@ -0,0 +1,174 @@
+// Contexts for tests that need to control when elements are sent, or check when they arrive.
+use std::sync::{Arc, Mutex};
+
+use dam::{channel::{ChannelElement, Receiver, Sender}, context::Context, dam_macros::context_macro, structures::Time, types::DAMType};
+
+use crate::control::ControlToken;
+
+// Sends every element at the given cycle, or as soon as the channel has room if that is later.
+#[context_macro]
+pub struct TimedGenerator<T: DAMType> {
+    elements: Vec<(u64, T)>,
+    output: Sender<T>
+}
+
+impl<T: DAMType> TimedGenerator<T> {
+    pub fn new(elements: Vec<(u64, T)>, output: Sender<T>) -> TimedGenerator<T> {
+        let generator = TimedGenerator { elements: elements, output: output, context_info: Default::default() };
+        generator.output.attach_sender(&generator);
+        generator
+    }
+}
+
+impl<T: DAMType> Context for TimedGenerator<T> {
+    fn init(&mut self) {}
+
+    fn run(&mut self) {
+        for (at, data) in std::mem::take(&mut self.elements) {
+            let at = Time::new(at);
+            if at > self.time.tick() {
+                self.time.advance(at);
+            }
+            self.output.enqueue(&self.time, ChannelElement::new(self.time.tick(), data)).unwrap();
+        }
+    }
+}
+
+// Checks a stream like a CheckerContext, and records the cycle at which every element arrived.
+#[context_macro]
+pub struct TimedChecker<T: DAMType + PartialEq> {
+    expected: Vec<T>,
+    input: Receiver<T>,
+    arrivals: Arc<Mutex<Vec<Time>>>
+}
+
+impl<T: DAMType + PartialEq> TimedChecker<T> {
+    pub fn new(expected: Vec<T>, input: Receiver<T>) -> TimedChecker<T> {
+        let checker = TimedChecker { expected: expected, input: input, arrivals: Default::default(), context_info: Default::default() };
+        checker.input.attach_receiver(&checker);
+        checker
+    }
+
+    // Filled in while the simulation runs.
+    pub fn arrivals(&self) -> Arc<Mutex<Vec<Time>>> {
+        self.arrivals.clone()
+    }
+}
+
+impl<T: DAMType + PartialEq> Context for TimedChecker<T> {
+    fn init(&mut self) {}
+
+    fn run(&mut self) {
+        for expected in std::mem::take(&mut self.expected) {
+            let element = self.input.dequeue(&self.time).expect("Stream ended before every expected element arrived.");
+            assert_eq!(element.data, expected);
+            self.arrivals.lock().unwrap().push(element.time);
+        }
+        assert!(self.input.dequeue(&self.time).is_err(), "Stream has more elements than expected.");
+    }
+}
+
+// Takes a stream in whatever order it arrives, and records every element with the cycle it arrived at.
+#[context_macro]
+pub struct Collector<T: DAMType> {
+    input: Receiver<T>,
+    received: Arc<Mutex<Vec<(Time, T)>>>
+}
+
+impl<T: DAMType> Collector<T> {
+    pub fn new(input: Receiver<T>) -> Collector<T> {
+        let collector = Collector { input: input, received: Default::default(), context_info: Default::default() };
+        collector.input.attach_receiver(&collector);
+        collector
+    }
+
+    // Filled in while the simulation runs.
+    pub fn received(&self) -> Arc<Mutex<Vec<(Time, T)>>> {
+        self.received.clone()
+    }
+}
+
+impl<T: DAMType> Context for Collector<T> {
+    fn init(&mut self) {}
+
+    fn run(&mut self) {
+        while let Ok(element) = self.input.dequeue(&self.time) {
+            self.received.lock().unwrap().push((element.time, element.data));
+        }
+    }
+}
+
+// Answers once `count` elements arrived: sends `reply` one cycle after the last of them, then closes. Until
+// then, its time only moves with the elements it receives.
+#[context_macro]
+pub struct Responder<T: DAMType> {
+    count: usize,
+    reply: T,
+    input: Receiver<T>,
+    output: Sender<T>
+}
+
+impl<T: DAMType> Responder<T> {
+    pub fn new(count: usize, reply: T, input: Receiver<T>, output: Sender<T>) -> Responder<T> {
+        let responder = Responder { count: count, reply: reply, input: input, output: output, context_info: Default::default() };
+        responder.input.attach_receiver(&responder);
+        responder.output.attach_sender(&responder);
+        responder
+    }
+}
+
+impl<T: DAMType> Context for Responder<T> {
+    fn init(&mut self) {}
+
+    fn run(&mut self) {
+        for _ in 0..self.count {
+            self.input.dequeue(&self.time).expect("Stream ended before the responder answered.");
+        }
+        self.output.enqueue(&self.time, ChannelElement::new(self.time.tick() + 1, self.reply.clone())).unwrap();
+    }
+}
+
+// Drives a unit through phases. A phase sends its config load, if any, then its elements one per cycle. With a 
+// done port, every phase ends with the done token of the unit, like a controller that sequences a pipeline. 
+// The load goes out before the elements, so the unit sees it before it takes them.
+#[context_macro]
+pub struct Controller<C: DAMType, T: DAMType> {
+    phases: Vec<(Option<C>, Vec<T>)>,
+    config: Sender<C>,
+    output: Sender<T>,
+    done: Option<Receiver<ControlToken>>
+}
+
+impl<C: DAMType, T: DAMType> Controller<C, T> {
+    pub fn new(phases: Vec<(Option<C>, Vec<T>)>, config: Sender<C>, output: Sender<T>) -> Controller<C, T> {
+        let controller = Controller { phases: phases, config: config, output: output, done: None, context_info: Default::default() };
+        controller.config.attach_sender(&controller);
+        controller.output.attach_sender(&controller);
+        controller
+    }
+
+    pub fn with_done(mut self, done: Receiver<ControlToken>) -> Controller<C, T> {
+        done.attach_receiver(&self);
+        self.done = Some(done);
+        self
+    }
+}
+
+impl<C: DAMType, T: DAMType> Context for Controller<C, T> {
+    fn init(&mut self) {}
+
+    fn run(&mut self) {
+        for (load, elements) in std::mem::take(&mut self.phases) {
+            if let Some(load) = load {
+                self.config.enqueue(&self.time, ChannelElement::new(self.time.tick(), load)).unwrap();
+            }
+            for element in elements {
+                self.output.enqueue(&self.time, ChannelElement::new(self.time.tick(), element)).unwrap();
+                self.time.incr_cycles(1);
+            }
+            if let Some(done) = &self.done {
+                done.dequeue(&self.time).expect("Unit finished before it signalled the end of a phase.");
+            }
+        }
+    }
+}
@@ -0,0 +1,18 @@
+// Types shared by the units and the networks between them.
+
+pub type Coordinate = (usize, usize); // (x, y), x grows towards East and y towards South
+
+// The way a packet still has to go along x and y. A packet that reached its column counts as heading East,
+// one that reached its row as heading South.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Heading {
+    pub east: bool,
+    pub south: bool
+}
+
+impl Heading {
+    pub const ALL: [Heading; 4] = [
+        Heading { east: true, south: true }, Heading { east: true, south: false },
+        Heading { east: false, south: true }, Heading { east: false, south: false }
+    ];
+}